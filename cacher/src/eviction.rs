@@ -0,0 +1,189 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, Hash},
+};
+
+/// Which value a full cache throws out to make room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The one that's gone longest without being asked for.
+    Lru,
+    /// The one that was worked out first, however much it's been used since.
+    Fifo,
+}
+
+/// Keeps track of cached keys to pick which to evict.
+pub(crate) trait Evict<U> {
+    /// Called when a value is cached for `key`.
+    fn inserted(&mut self, key: &U);
+
+    /// Called when a cached value is asked for.
+    fn accessed(&mut self, key: &U);
+
+    fn removed(&mut self, key: &U);
+
+    fn clear(&mut self);
+
+    /// Picks the next key to throw out, and forgets about it.
+    fn evict(&mut self) -> Option<U>;
+}
+
+/// Evicts whichever key was last used longest ago.
+pub(crate) struct Lru<U, S>(Order<U, S>);
+
+impl<U, S> Evict<U> for Lru<U, S>
+where
+    U: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn inserted(&mut self, key: &U) {
+        self.0.touch(key);
+    }
+
+    fn accessed(&mut self, key: &U) {
+        self.0.touch(key);
+    }
+
+    fn removed(&mut self, key: &U) {
+        self.0.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn evict(&mut self) -> Option<U> {
+        self.0.pop_oldest()
+    }
+}
+
+/// Evicts keys in the order they were cached.
+pub(crate) struct Fifo<U, S>(Order<U, S>);
+
+impl<U, S> Evict<U> for Fifo<U, S>
+where
+    U: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn inserted(&mut self, key: &U) {
+        self.0.touch(key);
+    }
+
+    fn accessed(&mut self, _: &U) {}
+
+    fn removed(&mut self, key: &U) {
+        self.0.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn evict(&mut self) -> Option<U> {
+        self.0.pop_oldest()
+    }
+}
+
+/// Whichever policy a cache was made with.
+pub(crate) enum Evictor<U, S> {
+    Lru(Lru<U, S>),
+    Fifo(Fifo<U, S>),
+}
+
+impl<U, S> Evictor<U, S>
+where
+    U: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    pub(crate) fn new(policy: EvictionPolicy, hasher: S) -> Evictor<U, S> {
+        match policy {
+            EvictionPolicy::Lru => Evictor::Lru(Lru(Order::with_hasher(hasher))),
+            EvictionPolicy::Fifo => Evictor::Fifo(Fifo(Order::with_hasher(hasher))),
+        }
+    }
+
+    fn policy(&mut self) -> &mut dyn Evict<U> {
+        match self {
+            Evictor::Lru(lru) => lru,
+            Evictor::Fifo(fifo) => fifo,
+        }
+    }
+}
+
+impl<U, S> Evict<U> for Evictor<U, S>
+where
+    U: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn inserted(&mut self, key: &U) {
+        self.policy().inserted(key);
+    }
+
+    fn accessed(&mut self, key: &U) {
+        self.policy().accessed(key);
+    }
+
+    fn removed(&mut self, key: &U) {
+        self.policy().removed(key);
+    }
+
+    fn clear(&mut self) {
+        self.policy().clear();
+    }
+
+    fn evict(&mut self) -> Option<U> {
+        self.policy().evict()
+    }
+}
+
+/// Keeps keys in the order they were last touched.
+struct Order<U, S> {
+    /// When each key was last touched.
+    touched: HashMap<U, u64, S>,
+    /// Keys by when they were last touched, oldest first.
+    order: BTreeMap<u64, U>,
+    clock: u64,
+}
+
+impl<U, S> Order<U, S>
+where
+    U: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn with_hasher(hasher: S) -> Order<U, S> {
+        Order {
+            touched: HashMap::with_hasher(hasher),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Moves `key` to the back, adding it if it's new.
+    fn touch(&mut self, key: &U) {
+        self.clock += 1;
+
+        if let Some(last) = self.touched.insert(key.clone(), self.clock) {
+            self.order.remove(&last);
+        }
+        self.order.insert(self.clock, key.clone());
+    }
+
+    fn remove(&mut self, key: &U) {
+        if let Some(last) = self.touched.remove(key) {
+            self.order.remove(&last);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.touched.clear();
+        self.order.clear();
+    }
+
+    /// Takes out the key touched longest ago.
+    fn pop_oldest(&mut self) -> Option<U> {
+        let (_, key) = self.order.pop_first()?;
+        self.touched.remove(&key);
+
+        Some(key)
+    }
+}
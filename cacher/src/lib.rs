@@ -1,13 +1,79 @@
-use std::{cmp, collections::HashMap, hash::Hash};
+use std::{
+    cmp,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    time::{Duration, Instant},
+};
 
-pub struct Cacher<T, U, V>
+mod eviction;
+mod sync_cacher;
+mod try_cacher;
+
+use eviction::{Evict, Evictor};
+
+pub use eviction::EvictionPolicy;
+pub use sync_cacher::SyncCacher;
+pub use try_cacher::TryCacher;
+
+/// Remembers what a calculation gave for each argument.
+///
+/// Keys are hashed with `S`, which is the same as `HashMap`'s by default.
+pub struct Cacher<T, U, V, S = RandomState>
 where
     T: Fn(U) -> V,
     U: Clone + cmp::Eq + Hash,
     V: Clone,
 {
     pub calculation: T,
-    pub values: HashMap<U, V>,
+    values: HashMap<U, V, S>,
+    /// The most values kept at once, if there's a limit.
+    capacity: Option<usize>,
+    /// How much the values can add up to, if there's a limit.
+    weight: Option<Weight<V>>,
+    /// Picks what to throw out. Only kept up for bounded caches.
+    evictor: Evictor<U, S>,
+    /// Told about each value thrown out to make room.
+    on_evict: Option<Box<dyn FnMut(U, V) + Send>>,
+    /// How long a value lasts before it's worked out again, if it expires.
+    ttl: Option<Duration>,
+    /// When each value was worked out.
+    stored: HashMap<U, Instant, S>,
+    stats: CacheStats,
+}
+
+/// Limits a cache by what its values weigh rather than how many there are.
+struct Weight<V> {
+    weigher: Box<dyn Fn(&V) -> usize + Send>,
+    max: usize,
+    /// What the cached values weigh together.
+    total: usize,
+}
+
+/// Saves the cached values as a map. The calculation isn't saved, so
+/// `Cacher::load` needs to be given it again.
+#[cfg(feature = "serde")]
+impl<T, U, V, S> serde::Serialize for Cacher<T, U, V, S>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash + serde::Serialize,
+    V: Clone + serde::Serialize,
+    S: BuildHasher + Clone,
+{
+    fn serialize<R>(&self, serializer: R) -> Result<R::Ok, R::Error>
+    where
+        R: serde::Serializer,
+    {
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// How often a cache has had the values asked of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Values that were already cached.
+    pub hits: u64,
+    /// Values that had to be worked out.
+    pub misses: u64,
 }
 
 impl<T, U, V> Cacher<T, U, V>
@@ -17,16 +83,382 @@ where
     V: Clone,
 {
     pub fn new(calculation: T) -> Cacher<T, U, V> {
+        Cacher::with_hasher(calculation, RandomState::new())
+    }
+
+    /// Makes a cache that starts out holding `entries`.
+    ///
+    /// The calculation isn't run for any of them, which makes this good for
+    /// warming a cache up with values saved from before.
+    pub fn from_entries<I>(calculation: T, entries: I) -> Cacher<T, U, V>
+    where
+        I: IntoIterator<Item = (U, V)>,
+    {
+        let mut cacher = Cacher::new(calculation);
+
+        for (key, value) in entries {
+            cacher.stored.insert(key.clone(), Instant::now());
+            cacher.values.insert(key, value);
+        }
+
+        cacher
+    }
+
+    /// Makes a cache holding the values in `deserializer`, as saved by
+    /// serializing a cache.
+    #[cfg(feature = "serde")]
+    pub fn load<'de, D>(calculation: T, deserializer: D) -> Result<Cacher<T, U, V>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+        U: serde::Deserialize<'de>,
+        V: serde::Deserialize<'de>,
+    {
+        let entries: HashMap<U, V> = serde::Deserialize::deserialize(deserializer)?;
+
+        Ok(Cacher::from_entries(calculation, entries))
+    }
+
+    /// Makes a cache that keeps at most `capacity` values.
+    ///
+    /// Once it's full, working out a new value throws out the one that's gone
+    /// longest without being asked for.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero, since the value just worked out would
+    /// have nowhere to go.
+    pub fn with_capacity(calculation: T, capacity: usize) -> Cacher<T, U, V> {
+        Cacher::with_policy(calculation, capacity, EvictionPolicy::Lru)
+    }
+
+    /// Makes a cache that keeps at most `capacity` values, with `policy`
+    /// picking which to throw out once it's full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_policy(calculation: T, capacity: usize, policy: EvictionPolicy) -> Cacher<T, U, V> {
+        assert!(capacity > 0, "a cache needs room for at least one value");
+
+        Cacher {
+            capacity: Some(capacity),
+            evictor: Evictor::new(policy, RandomState::new()),
+            ..Cacher::new(calculation)
+        }
+    }
+
+    /// Makes a cache whose values weigh at most `max_weight` together, as
+    /// measured by `weigher`.
+    ///
+    /// Once a new value won't fit, values are thrown out, least recently used
+    /// first, until it does. A value weighing more than `max_weight` alone is
+    /// still cached, once everything else has gone.
+    pub fn with_weigher<W>(calculation: T, max_weight: usize, weigher: W) -> Cacher<T, U, V>
+    where
+        W: Fn(&V) -> usize + Send + 'static,
+    {
+        Cacher {
+            weight: Some(Weight {
+                weigher: Box::new(weigher),
+                max: max_weight,
+                total: 0,
+            }),
+            ..Cacher::new(calculation)
+        }
+    }
+
+    /// Makes a cache whose values are worked out again once they're older
+    /// than `ttl`.
+    pub fn with_ttl(calculation: T, ttl: Duration) -> Cacher<T, U, V> {
+        Cacher {
+            ttl: Some(ttl),
+            ..Cacher::new(calculation)
+        }
+    }
+}
+
+impl<T, U, V, S> Cacher<T, U, V, S>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    /// Makes a cache that hashes keys with `hasher`.
+    ///
+    /// A faster hasher than the default can pay off for keys that don't come
+    /// from anyone untrusted, like small integers.
+    pub fn with_hasher(calculation: T, hasher: S) -> Cacher<T, U, V, S> {
         Cacher {
             calculation,
-            values: HashMap::new(),
+            values: HashMap::with_hasher(hasher.clone()),
+            capacity: None,
+            weight: None,
+            evictor: Evictor::new(EvictionPolicy::Lru, hasher.clone()),
+            on_evict: None,
+            ttl: None,
+            stored: HashMap::with_hasher(hasher),
+            stats: CacheStats::default(),
         }
     }
 
+    /// Sets how long values last before they're worked out again.
+    ///
+    /// This goes for values already cached as well, counting from when each
+    /// was worked out.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = Some(ttl);
+    }
+
+    /// Calls `on_evict` with each key and value thrown out to make room in a
+    /// full cache.
+    ///
+    /// It's called before the value taking its place is cached. Values that
+    /// are removed, cleared or expire don't count.
+    pub fn set_on_evict<F>(&mut self, on_evict: F)
+    where
+        F: FnMut(U, V) + Send + 'static,
+    {
+        self.on_evict = Some(Box::new(on_evict));
+    }
+
     pub fn value(&mut self, arg: U) -> &V {
+        self.fetch(arg, |calculation, arg| calculation(arg))
+    }
+
+    /// Like `value`, but with `f` working out the value if it isn't cached,
+    /// instead of the cache's own calculation.
+    ///
+    /// Handy for caching a value that's already at hand.
+    pub fn compute_if_absent<F>(&mut self, key: U, f: F) -> &V
+    where
+        F: FnOnce(&U) -> V,
+    {
+        self.fetch(key, |_, key| f(&key))
+    }
+
+    fn fetch<F>(&mut self, arg: U, compute: F) -> &V
+    where
+        F: FnOnce(&T, U) -> V,
+    {
+        if self.is_expired(&arg) {
+            self.take(&arg);
+        }
+
+        if self.values.contains_key(&arg) {
+            self.stats.hits += 1;
+
+            if self.is_bounded() {
+                self.evictor.accessed(&arg);
+            }
+        } else {
+            self.stats.misses += 1;
+
+            let value = compute(&self.calculation, arg.clone());
+            let weight = self.weigh(&value);
+            self.make_room(weight);
+
+            self.values.insert(arg.clone(), value);
+            self.stored.insert(arg.clone(), Instant::now());
+
+            if let Some(budget) = &mut self.weight {
+                budget.total += weight;
+            }
+            if self.is_bounded() {
+                self.evictor.inserted(&arg);
+            }
+        }
+
+        &self.values[&arg]
+    }
+
+    /// Looks up or works out the value for each of `keys`, in order.
+    ///
+    /// Each key counts just as it would through `value`.
+    pub fn value_many<I>(&mut self, keys: I) -> Vec<V>
+    where
+        I: IntoIterator<Item = U>,
+    {
+        keys.into_iter()
+            .map(|key| self.value(key).clone())
+            .collect()
+    }
+
+    /// Looks up the value cached for `key`, without working it out if there
+    /// isn't one.
+    ///
+    /// Doesn't count as using the value, so it won't keep it from eviction.
+    pub fn get(&self, key: &U) -> Option<&V> {
+        if self.is_expired(key) {
+            return None;
+        }
+
+        self.values.get(key)
+    }
+
+    /// Goes through the cached keys and values, in no particular order.
+    ///
+    /// Expired values are left out. Like `get`, this doesn't count as using
+    /// anything.
+    pub fn iter(&self) -> impl Iterator<Item = (&U, &V)> + '_ {
         self.values
-            .entry(arg.clone())
-            .or_insert((self.calculation)(arg.clone()))
+            .iter()
+            .filter(move |(key, _)| !self.is_expired(key))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &U> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values_iter(&self) -> impl Iterator<Item = &V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// How many of the values asked for were cached already.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// The share of values asked for that were cached already, from 0 to 1.
+    ///
+    /// A cache that's never been asked for anything has a rate of 0.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.stats.hits + self.stats.misses;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.stats.hits as f64 / total as f64
+        }
+    }
+
+    /// The number of values cached.
+    ///
+    /// Expired values count until they're asked for again.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Whether there's an unexpired value cached for `key`.
+    pub fn contains_key(&self, key: &U) -> bool {
+        self.values.contains_key(key) && !self.is_expired(key)
+    }
+
+    /// Forgets a value, returning it if it was cached.
+    ///
+    /// The next time it's asked for it's worked out again.
+    pub fn remove(&mut self, key: &U) -> Option<V> {
+        self.evictor.removed(key);
+
+        self.take(key)
+    }
+
+    /// Forgets every value.
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.evictor.clear();
+        self.stored.clear();
+
+        if let Some(budget) = &mut self.weight {
+            budget.total = 0;
+        }
+    }
+
+    /// Keeps only the values `f` returns true for, as with `HashMap::retain`.
+    ///
+    /// `f` can change the values it keeps as well.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&U, &mut V) -> bool,
+    {
+        let mut dropped = Vec::new();
+        self.values.retain(|key, value| {
+            let keep = f(key, value);
+            if !keep {
+                dropped.push(key.clone());
+            }
+
+            keep
+        });
+
+        for key in &dropped {
+            self.evictor.removed(key);
+            self.stored.remove(key);
+        }
+
+        // The values kept may weigh something else now
+        if let Some(budget) = &mut self.weight {
+            budget.total = self
+                .values
+                .values()
+                .map(|value| (budget.weigher)(value))
+                .sum();
+        }
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.capacity.is_some() || self.weight.is_some()
+    }
+
+    fn weigh(&self, value: &V) -> usize {
+        self.weight
+            .as_ref()
+            .map_or(0, |budget| (budget.weigher)(value))
+    }
+
+    /// Evicts values until there's room for one weighing `incoming`.
+    fn make_room(&mut self, incoming: usize) {
+        while !self.values.is_empty() && self.is_full(incoming) {
+            let oldest = match self.evictor.evict() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+
+            if let Some(value) = self.take(&oldest) {
+                if let Some(on_evict) = &mut self.on_evict {
+                    on_evict(oldest, value);
+                }
+            }
+        }
+    }
+
+    fn is_full(&self, incoming: usize) -> bool {
+        let counted = self
+            .capacity
+            .is_some_and(|capacity| self.values.len() >= capacity);
+        let weighed = self
+            .weight
+            .as_ref()
+            .is_some_and(|budget| budget.total + incoming > budget.max);
+
+        counted || weighed
+    }
+
+    /// Takes a value out, leaving the evictor to whoever's calling.
+    fn take(&mut self, key: &U) -> Option<V> {
+        self.stored.remove(key);
+        let value = self.values.remove(key)?;
+
+        let weight = self.weigh(&value);
+        if let Some(budget) = &mut self.weight {
+            budget.total = budget.total.saturating_sub(weight);
+        }
+
+        Some(value)
+    }
+
+    fn is_expired(&self, key: &U) -> bool {
+        match self.ttl {
+            Some(ttl) => self
+                .stored
+                .get(key)
+                .is_some_and(|stored| stored.elapsed() >= ttl),
+            None => false,
+        }
     }
 }
 
@@ -43,4 +475,326 @@ mod tests {
 
         assert_eq!(*v2, 2);
     }
+
+    #[test]
+    fn calculation_runs_once_per_value() {
+        let calls = std::cell::Cell::new(0);
+        let mut c = Cacher::new(|a: u32| {
+            calls.set(calls.get() + 1);
+            a
+        });
+
+        c.value(1);
+        c.value(1);
+
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut c = Cacher::with_capacity(|a: u32| a, 2);
+
+        c.value(1);
+        c.value(2);
+        // Using 1 again leaves 2 as the oldest
+        c.value(1);
+        c.value(3);
+
+        assert!(c.contains_key(&1));
+        assert!(!c.contains_key(&2));
+        assert!(c.contains_key(&3));
+        assert_eq!(c.len(), 2);
+    }
+
+    #[test]
+    fn expired_values_are_worked_out_again() {
+        let calls = std::cell::Cell::new(0);
+        let mut c = Cacher::with_ttl(
+            |a: u32| {
+                calls.set(calls.get() + 1);
+                a
+            },
+            Duration::from_millis(20),
+        );
+
+        c.value(1);
+        c.value(1);
+        assert_eq!(calls.get(), 1);
+
+        std::thread::sleep(Duration::from_millis(30));
+
+        c.value(1);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn removed_values_are_worked_out_again() {
+        let calls = std::cell::Cell::new(0);
+        let mut c = Cacher::new(|a: u32| {
+            calls.set(calls.get() + 1);
+            a * 10
+        });
+
+        c.value(1);
+        c.value(2);
+
+        assert_eq!(c.remove(&1), Some(10));
+        assert_eq!(c.remove(&1), None);
+
+        c.value(1);
+        assert_eq!(calls.get(), 3);
+
+        c.clear();
+        assert!(c.is_empty());
+
+        c.value(2);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn size_and_membership() {
+        let mut c = Cacher::new(|a: u32| a);
+
+        assert_eq!(c.len(), 0);
+        assert!(c.is_empty());
+        assert!(!c.contains_key(&1));
+
+        c.value(1);
+        c.value(2);
+
+        assert_eq!(c.len(), 2);
+        assert!(!c.is_empty());
+        assert!(c.contains_key(&1));
+        assert!(!c.contains_key(&3));
+    }
+
+    #[test]
+    fn get_never_works_values_out() {
+        let calls = std::cell::Cell::new(0);
+        let mut c = Cacher::new(|a: u32| {
+            calls.set(calls.get() + 1);
+            a
+        });
+
+        assert_eq!(c.get(&1), None);
+        assert!(c.is_empty());
+        assert_eq!(calls.get(), 0);
+
+        c.value(1);
+        assert_eq!(c.get(&1), Some(&1));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let mut c = Cacher::new(|a: u32| a);
+        assert_eq!(c.hit_rate(), 0.0);
+
+        c.value(1);
+        c.value(1);
+        c.value(2);
+        c.value(1);
+        c.get(&2);
+
+        assert_eq!(c.stats(), CacheStats { hits: 2, misses: 2 });
+        assert_eq!(c.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn custom_hashers() {
+        use std::{collections::hash_map::DefaultHasher, hash::BuildHasherDefault};
+
+        let mut c = Cacher::with_hasher(
+            |a: u32| a + 1,
+            BuildHasherDefault::<DefaultHasher>::default(),
+        );
+
+        assert_eq!(*c.value(1), 2);
+        assert_eq!(c.get(&1), Some(&2));
+        assert_eq!(c.len(), 1);
+    }
+
+    #[test]
+    fn policies_pick_different_victims() {
+        let survivors = |policy| {
+            let mut c = Cacher::with_policy(|a: u32| a, 2, policy);
+
+            c.value(1);
+            c.value(2);
+            c.value(1);
+            c.value(3);
+
+            (c.contains_key(&1), c.contains_key(&2), c.contains_key(&3))
+        };
+
+        // Using 1 again saves it from LRU, but FIFO only cares that it came first
+        assert_eq!(survivors(EvictionPolicy::Lru), (true, false, true));
+        assert_eq!(survivors(EvictionPolicy::Fifo), (false, true, true));
+    }
+
+    #[test]
+    fn iterating_borrows_the_entries() {
+        let mut c = Cacher::new(|a: u32| a * 10);
+
+        c.value(1);
+        c.value(2);
+        c.value(3);
+
+        let mut entries: Vec<_> = c.iter().map(|(&k, &v)| (k, v)).collect();
+        entries.sort();
+        assert_eq!(entries, vec![(1, 10), (2, 20), (3, 30)]);
+
+        let mut keys: Vec<_> = c.keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2, 3]);
+
+        assert_eq!(c.values_iter().sum::<u32>(), 60);
+    }
+
+    #[test]
+    fn many_values_at_once() {
+        let calls = std::cell::Cell::new(0);
+        let mut c = Cacher::new(|a: u32| {
+            calls.set(calls.get() + 1);
+            a * 10
+        });
+
+        c.value(2);
+
+        assert_eq!(c.value_many(vec![3, 2, 1, 3]), vec![30, 20, 10, 30]);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(c.stats(), CacheStats { hits: 2, misses: 3 });
+    }
+
+    #[test]
+    fn entries_are_cached_without_calculating() {
+        let c = Cacher::from_entries(
+            |_: u32| -> u32 { panic!("should be cached") },
+            vec![(1, 10)],
+        );
+
+        assert_eq!(c.get(&1), Some(&10));
+        assert_eq!(c.len(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn saved_caches_load_back() {
+        let mut c = Cacher::new(|a: u32| a.to_string());
+        c.value_many(vec![1, 2, 3]);
+
+        let saved = serde_json::to_string(&c).unwrap();
+
+        let calls = std::cell::Cell::new(0);
+        let mut loaded = Cacher::load(
+            |a: u32| {
+                calls.set(calls.get() + 1);
+                a.to_string()
+            },
+            &mut serde_json::Deserializer::from_str(&saved),
+        )
+        .unwrap();
+
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.value_many(vec![1, 2, 3]), vec!["1", "2", "3"]);
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn evictions_are_reported() {
+        use std::sync::{Arc, Mutex};
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut c = Cacher::with_capacity(|a: u32| a * 10, 2);
+
+        let seen = Arc::clone(&evicted);
+        c.set_on_evict(move |key, value| seen.lock().unwrap().push((key, value)));
+
+        c.value(1);
+        c.value(2);
+        c.value(1);
+        c.value(3);
+        c.remove(&3);
+
+        assert_eq!(*evicted.lock().unwrap(), vec![(2, 20)]);
+    }
+
+    #[test]
+    fn weighed_caches_keep_to_their_budget() {
+        let mut c =
+            Cacher::with_weigher(|n: usize| vec![0_u8; n], 10, |value: &Vec<u8>| value.len());
+
+        c.value(4);
+        c.value(5);
+        assert_eq!(c.len(), 2);
+
+        // Two small values can go to fit one big one
+        c.value(6);
+        assert!(!c.contains_key(&4));
+        assert!(c.contains_key(&6));
+        assert_eq!(c.len(), 1);
+
+        for n in [1, 1, 1] {
+            c.value(n);
+        }
+        assert_eq!(c.len(), 2);
+        assert!(c.contains_key(&1) && c.contains_key(&6));
+    }
+
+    #[test]
+    fn values_can_be_given_when_absent() {
+        let mut c = Cacher::new(|a: u32| a);
+        let mut calls = 0;
+
+        assert_eq!(
+            *c.compute_if_absent(1, |_| {
+                calls += 1;
+                100
+            }),
+            100
+        );
+        assert_eq!(
+            *c.compute_if_absent(1, |_| {
+                calls += 1;
+                200
+            }),
+            100
+        );
+
+        assert_eq!(calls, 1);
+        assert_eq!(*c.value(1), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one value")]
+    fn zero_capacity_is_rejected() {
+        Cacher::with_capacity(|a: u32| a, 0);
+    }
+
+    #[test]
+    fn retain_keeps_matching_values() {
+        let mut c = Cacher::with_capacity(|a: u32| a, 10);
+        c.value_many(0..10);
+
+        c.retain(|key, _| key % 2 == 0);
+
+        assert_eq!(c.len(), 5);
+        assert!(c.keys().all(|key| key % 2 == 0));
+        assert!(!c.contains_key(&3));
+
+        // What's left can fill back up without evicting early
+        c.value_many(10..15);
+        assert_eq!(c.len(), 10);
+    }
+
+    #[test]
+    fn unbounded_caches_keep_everything() {
+        let mut c = Cacher::new(|a: u32| a);
+
+        for a in 0..100 {
+            c.value(a);
+        }
+
+        assert_eq!(c.len(), 100);
+    }
 }
@@ -7,5 +7,5 @@ fn main() {
     c.value((2, 2));
     c.value((3, 3));
 
-    println!("{:?}", c.values);
+    println!("{:?}", c.iter().collect::<Vec<_>>());
 }
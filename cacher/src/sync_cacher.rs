@@ -0,0 +1,241 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Condvar, Mutex, RwLock},
+};
+
+/// A cache that can be shared between threads.
+///
+/// Values are handed out as clones, so wrapping them in an `Arc` keeps that
+/// cheap. Lookups of cached values only share a read lock with each other.
+/// When several threads ask for the same missing value at once, one works it
+/// out while the rest wait to share it.
+pub struct SyncCacher<T, U, V>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    calculation: T,
+    values: RwLock<HashMap<U, V>>,
+    /// Values being worked out right now, for others wanting them to wait on.
+    in_flight: Mutex<HashMap<U, Arc<Flight<V>>>>,
+}
+
+/// A value one thread is working out for others.
+struct Flight<V> {
+    state: Mutex<FlightState<V>>,
+    landed: Condvar,
+}
+
+enum FlightState<V> {
+    Running,
+    Done(V),
+    /// The calculation panicked, so those waiting have to try for themselves.
+    Abandoned,
+}
+
+impl<T, U, V> SyncCacher<T, U, V>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    pub fn new(calculation: T) -> SyncCacher<T, U, V> {
+        SyncCacher {
+            calculation,
+            values: RwLock::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn value(&self, arg: U) -> V {
+        if let Some(value) = self.get(&arg) {
+            return value;
+        }
+
+        let flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get(&arg) {
+                Some(flight) => Some(Arc::clone(flight)),
+                None => {
+                    // It may have landed since the first look
+                    if let Some(value) = self.get(&arg) {
+                        return value;
+                    }
+
+                    let flight = Arc::new(Flight {
+                        state: Mutex::new(FlightState::Running),
+                        landed: Condvar::new(),
+                    });
+                    in_flight.insert(arg.clone(), flight);
+
+                    None
+                }
+            }
+        };
+
+        match flight {
+            Some(flight) => self.wait_for(&flight, arg),
+            None => self.calculate(arg),
+        }
+    }
+
+    /// Works out a value for those waiting on its flight.
+    fn calculate(&self, arg: U) -> V {
+        // Lets the others go even if the calculation panics
+        let mut landing = Landing {
+            cacher: self,
+            key: &arg,
+            value: None,
+        };
+
+        // Worked out without the lock, so other lookups aren't held up by it
+        let value = (self.calculation)(arg.clone());
+
+        self.values
+            .write()
+            .unwrap()
+            .insert(arg.clone(), value.clone());
+        landing.value = Some(value.clone());
+
+        value
+    }
+
+    fn wait_for(&self, flight: &Flight<V>, arg: U) -> V {
+        let mut state = flight.state.lock().unwrap();
+
+        loop {
+            match &*state {
+                FlightState::Running => state = flight.landed.wait(state).unwrap(),
+                FlightState::Done(value) => return value.clone(),
+                FlightState::Abandoned => {
+                    drop(state);
+                    return self.value(arg);
+                }
+            }
+        }
+    }
+
+    /// Looks up the value cached for `key`, without working it out if there
+    /// isn't one.
+    pub fn get(&self, key: &U) -> Option<V> {
+        self.values.read().unwrap().get(key).cloned()
+    }
+
+    /// Forgets a value, returning it if it was cached.
+    pub fn remove(&self, key: &U) -> Option<V> {
+        self.values.write().unwrap().remove(key)
+    }
+
+    pub fn clear(&self) {
+        self.values.write().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.read().unwrap().is_empty()
+    }
+}
+
+/// Ends a flight when the thread working it out is done, one way or another.
+struct Landing<'a, T, U, V>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    cacher: &'a SyncCacher<T, U, V>,
+    key: &'a U,
+    value: Option<V>,
+}
+
+impl<T, U, V> Drop for Landing<'_, T, U, V>
+where
+    T: Fn(U) -> V,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        let flight = self.cacher.in_flight.lock().unwrap().remove(self.key);
+
+        if let Some(flight) = flight {
+            *flight.state.lock().unwrap() = match self.value.take() {
+                Some(value) => FlightState::Done(value),
+                None => FlightState::Abandoned,
+            };
+            flight.landed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn shared_between_threads() {
+        let c = Arc::new(SyncCacher::new(|a: u32| a * 2));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let c = Arc::clone(&c);
+
+                thread::spawn(move || {
+                    for a in 0..1000 {
+                        assert_eq!(c.value(a % 100), (a % 100) * 2);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(c.len(), 100);
+    }
+
+    #[test]
+    fn concurrent_misses_share_one_calculation() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let c = Arc::new(SyncCacher::new(move |a: u32| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            a
+        }));
+        let start = Arc::new(Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let c = Arc::clone(&c);
+                let start = Arc::clone(&start);
+
+                thread::spawn(move || {
+                    start.wait();
+                    c.value(7)
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 7);
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{cmp, collections::HashMap, hash::Hash};
+
+/// A cache for calculations that can fail.
+///
+/// Only values that were worked out successfully are kept. Errors go back to
+/// the caller and the next call for the same key tries again.
+pub struct TryCacher<T, U, V, E>
+where
+    T: Fn(U) -> Result<V, E>,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    pub calculation: T,
+    values: HashMap<U, V>,
+}
+
+impl<T, U, V, E> TryCacher<T, U, V, E>
+where
+    T: Fn(U) -> Result<V, E>,
+    U: Clone + cmp::Eq + Hash,
+    V: Clone,
+{
+    pub fn new(calculation: T) -> TryCacher<T, U, V, E> {
+        TryCacher {
+            calculation,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn value(&mut self, arg: U) -> Result<&V, E> {
+        if !self.values.contains_key(&arg) {
+            let value = (self.calculation)(arg.clone())?;
+            self.values.insert(arg.clone(), value);
+        }
+
+        Ok(&self.values[&arg])
+    }
+
+    /// Looks up the value cached for `key`, without working it out if there
+    /// isn't one.
+    pub fn get(&self, key: &U) -> Option<&V> {
+        self.values.get(key)
+    }
+
+    /// Forgets a value, returning it if it was cached.
+    pub fn remove(&mut self, key: &U) -> Option<V> {
+        self.values.remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn errors_are_not_cached() {
+        let calls = Cell::new(0);
+        let mut c = TryCacher::new(|a: u32| {
+            calls.set(calls.get() + 1);
+
+            if calls.get() == 1 {
+                Err("not yet")
+            } else {
+                Ok(a)
+            }
+        });
+
+        assert_eq!(c.value(1), Err("not yet"));
+        assert!(c.is_empty());
+
+        assert_eq!(c.value(1), Ok(&1));
+        assert_eq!(c.value(1), Ok(&1));
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -0,0 +1,121 @@
+//! Compares the pool against a plain single-queue pool on lots of tiny jobs,
+//! where contention over the queue is what costs the most, and batches of
+//! jobs against the same jobs given one at a time.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use server::thread_pool::{Job, ThreadPool};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+const WORKERS: usize = 4;
+const SUBMITTERS: usize = 4;
+const JOBS_PER_SUBMITTER: usize = 10_000;
+
+/// Every job goes through one lock, as the pool's did before it dealt jobs
+/// out to its workers.
+struct SingleQueue {
+    sender: Mutex<mpsc::Sender<Job>>,
+}
+
+impl SingleQueue {
+    fn new(size: usize) -> SingleQueue {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size {
+            let receiver = Arc::clone(&receiver);
+
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+
+        SingleQueue {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    fn execute(&self, job: Job) {
+        self.sender.lock().unwrap().send(job).unwrap();
+    }
+}
+
+/// A job that only counts itself done.
+fn small_job(left: &Arc<AtomicUsize>) -> impl FnOnce() + Send + 'static {
+    let left = Arc::clone(left);
+
+    move || {
+        left.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Has several threads at once each submit `JOBS_PER_SUBMITTER` tiny jobs,
+/// and waits for them all.
+fn run_small_jobs<F>(submit: &F)
+where
+    F: Fn(&Arc<AtomicUsize>) + Sync,
+{
+    let left = Arc::new(AtomicUsize::new(SUBMITTERS * JOBS_PER_SUBMITTER));
+
+    thread::scope(|scope| {
+        for _ in 0..SUBMITTERS {
+            let left = &left;
+            scope.spawn(move || submit(left));
+        }
+    });
+
+    while left.load(Ordering::Acquire) > 0 {
+        thread::yield_now();
+    }
+}
+
+fn small_jobs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("small_jobs");
+    group.throughput(Throughput::Elements(
+        (SUBMITTERS * JOBS_PER_SUBMITTER) as u64,
+    ));
+
+    let pool = ThreadPool::new(WORKERS).unwrap();
+    group.bench_function(BenchmarkId::new("pool", WORKERS), |b| {
+        b.iter(|| {
+            run_small_jobs(&|left| {
+                for _ in 0..JOBS_PER_SUBMITTER {
+                    pool.execute(small_job(left)).unwrap();
+                }
+            })
+        })
+    });
+    group.bench_function(BenchmarkId::new("pool_batch", WORKERS), |b| {
+        b.iter(|| {
+            run_small_jobs(&|left| {
+                let jobs = (0..JOBS_PER_SUBMITTER).map(|_| Box::new(small_job(left)) as Job);
+                pool.execute_batch(jobs).unwrap();
+            })
+        })
+    });
+
+    let single = SingleQueue::new(WORKERS);
+    group.bench_function(BenchmarkId::new("single_queue", WORKERS), |b| {
+        b.iter(|| {
+            run_small_jobs(&|left| {
+                for _ in 0..JOBS_PER_SUBMITTER {
+                    single.execute(Box::new(small_job(left)));
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, small_jobs);
+criterion_main!(benches);
@@ -11,5 +11,8 @@ fn main() {
 
     let server = WebServer::new(THREAD_LIMIT, routes);
 
+    #[cfg(feature = "signals")]
+    server.shutdown_on_signals().unwrap();
+
     server.start("127.0.0.1:7878").unwrap();
 }
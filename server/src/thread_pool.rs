@@ -1,20 +1,312 @@
 use std::{
+    any::Any,
+    cell::RefCell,
+    collections::VecDeque,
     error::Error,
-    fmt,
-    sync::{mpsc, Arc, Mutex},
+    fmt, io, iter,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+mod timer;
 
-enum Message {
-    NewJob(Job),
-    Terminate,
+use timer::Timer;
+
+pub use timer::TaskHandle;
+
+/// A boxed job, as taken by `ThreadPool::execute_batch`.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Makes the state a worker hands its jobs.
+type Init = dyn Fn() -> Box<dyn Any> + Send + Sync;
+
+/// Told about every job that panics.
+type OnPanic = dyn Fn(Box<dyn Any + Send>) + Send + Sync;
+
+thread_local! {
+    /// The state made for the worker running on this thread, if any.
+    static WORKER_STATE: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
 }
 
+/// How many times a worker out of jobs looks again before it goes to sleep.
+const IDLE_SPINS: usize = 16;
+
+/// Jobs handed to a worker but not yet started.
+type Local = Mutex<VecDeque<Job>>;
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
+    /// Started the first time a job is delayed.
+    timer: Mutex<Option<Timer>>,
+}
+
+/// What the pool and its workers share.
+struct Shared {
+    workers: Mutex<Vec<Worker>>,
+    /// Each worker's jobs, by worker id. Jobs are dealt out among these, and
+    /// workers with none of their own steal from the others.
+    locals: RwLock<Vec<(usize, Arc<Local>)>>,
+    /// Which worker the next job is dealt to.
+    next_local: AtomicUsize,
+    /// The most jobs that may wait for a worker, if there's a limit.
+    bound: Option<usize>,
+    /// Held by workers deciding whether to wait for a job, and by whoever
+    /// wakes them.
+    sleep: Mutex<Sleep>,
+    /// Signalled when there's a job for a waiting worker, or it should stop.
+    wakeup: Condvar,
+    /// Signalled when a bounded pool may have room for another job.
+    room: Condvar,
+    /// Workers waiting on `wakeup`. Only changed with `sleep` held, but read
+    /// without it so submitting needn't take the lock when nobody's waiting.
+    sleepers: AtomicUsize,
+    next_id: AtomicUsize,
+    /// Jobs waiting for a worker.
+    queued: AtomicUsize,
+    /// Jobs a worker is running.
+    active: AtomicUsize,
+    /// Signalled whenever the last job running finishes with none queued.
+    idle: Condvar,
+    idle_lock: Mutex<()>,
+    /// Worker threads are named this followed by their id.
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    init: Option<Arc<Init>>,
+    on_panic: Option<Arc<OnPanic>>,
+    /// Set once the pool starts shutting down, after which it takes no jobs.
+    closed: AtomicBool,
+    /// Workers wait on `resumed` before looking for a job while this is set.
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+/// What waiting workers are told, kept under `Shared::sleep`.
+#[derive(Default)]
+struct Sleep {
+    /// Waiting workers already woken for a job, which don't count as idle.
+    wakeups: usize,
+    /// Workers to let go once there's nothing left to do.
+    retiring: usize,
+    /// Set when every worker should stop once there's nothing left to do.
+    stopping: bool,
+}
+
+/// Why a job couldn't be given to a pool without waiting.
+enum SubmitError {
+    /// A bounded pool had no room for it.
+    Full(Job),
+    Closed,
+}
+
+impl Shared {
+    fn is_idle(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) == 0 && self.active.load(Ordering::SeqCst) == 0
+    }
+
+    /// Gives a job to the pool, waiting for room if it's bounded and full.
+    fn submit(&self, job: Job) -> Result<(), ExecuteError> {
+        let bound = match self.bound {
+            Some(bound) => bound,
+            None => {
+                return self
+                    .submit_unbounded(iter::once(job))
+                    .map_err(|_| ExecuteError)
+            }
+        };
+
+        let mut sleep = self.sleep.lock().unwrap();
+        let mut job = job;
+
+        loop {
+            match self.submit_bounded(&mut sleep, bound, job) {
+                Ok(()) => return Ok(()),
+                Err(SubmitError::Full(unsent)) => {
+                    job = unsent;
+                    sleep = self.room.wait(sleep).unwrap();
+                }
+                Err(SubmitError::Closed) => return Err(ExecuteError),
+            }
+        }
+    }
+
+    /// Gives a job to the pool unless it's bounded and full.
+    fn try_submit(&self, job: Job) -> Result<(), SubmitError> {
+        match self.bound {
+            Some(bound) => self.submit_bounded(&mut self.sleep.lock().unwrap(), bound, job),
+            None => self
+                .submit_unbounded(iter::once(job))
+                .map_err(|_| SubmitError::Closed),
+        }
+    }
+
+    /// Deals jobs out to the workers and wakes any waiting for them.
+    ///
+    /// Gives the jobs back if the pool has shut down.
+    fn submit_unbounded<I>(&self, jobs: I) -> Result<(), I>
+    where
+        I: ExactSizeIterator<Item = Job>,
+    {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(jobs);
+        }
+
+        let count = jobs.len();
+        self.deal(jobs)?;
+
+        // Nobody's waiting most of the time, so skip the lock then
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let mut sleep = self.sleep.lock().unwrap();
+            self.wake(&mut sleep, count);
+        }
+
+        Ok(())
+    }
+
+    /// Deals a job out if a bounded pool has room for it.
+    ///
+    /// Called with `sleep` held, which keeps other submitters from taking
+    /// the same room.
+    fn submit_bounded(&self, sleep: &mut Sleep, bound: usize, job: Job) -> Result<(), SubmitError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(SubmitError::Closed);
+        }
+
+        if !self.has_room(sleep, bound) {
+            return Err(SubmitError::Full(job));
+        }
+
+        if self.deal(iter::once(job)).is_err() {
+            return Err(SubmitError::Closed);
+        }
+
+        self.wake(sleep, 1);
+
+        Ok(())
+    }
+
+    /// Whether a bounded pool can take another job. One that a waiting worker
+    /// will pick up straight away doesn't count against the bound.
+    fn has_room(&self, sleep: &Sleep, bound: usize) -> bool {
+        let idle = self.sleepers.load(Ordering::SeqCst) - sleep.wakeups;
+
+        self.queued.load(Ordering::SeqCst) < bound + idle
+    }
+
+    /// Spreads jobs over the workers' deques, a run of them to each, taking
+    /// each deque's lock once.
+    ///
+    /// Gives the jobs back if there are no workers to give them to.
+    fn deal<I>(&self, mut jobs: I) -> Result<(), I>
+    where
+        I: ExactSizeIterator<Item = Job>,
+    {
+        // Held while pushing, so a worker can't be forgotten with jobs in hand
+        let locals = self.locals.read().unwrap();
+        if locals.is_empty() {
+            return Err(jobs);
+        }
+        if jobs.len() == 0 {
+            return Ok(());
+        }
+
+        let shares = jobs.len().min(locals.len());
+        let first = self.next_local.fetch_add(shares, Ordering::Relaxed);
+
+        for i in 0..shares {
+            let (_, local) = &locals[(first + i) % locals.len()];
+            let mut local = local.lock().unwrap();
+
+            // Counted before the lock goes, so a job is never taken before
+            // it's counted, nor counted before it can be taken
+            let before = local.len();
+            let share = jobs.len().div_ceil(shares - i);
+            local.extend(jobs.by_ref().take(share));
+            self.queued
+                .fetch_add(local.len() - before, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Wakes up to `count` waiting workers that haven't been woken already.
+    fn wake(&self, sleep: &mut Sleep, count: usize) {
+        let idle = self.sleepers.load(Ordering::SeqCst) - sleep.wakeups;
+
+        for _ in 0..count.min(idle) {
+            sleep.wakeups += 1;
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Takes the newest job another worker is holding on to.
+    fn steal(&self, thief: usize) -> Option<Job> {
+        let locals = self.locals.read().unwrap();
+
+        locals
+            .iter()
+            .filter(|(id, _)| *id != thief)
+            .find_map(|(_, local)| local.lock().unwrap().pop_back())
+    }
+
+    /// Waits until there might be a job to take.
+    ///
+    /// Returns false once the worker should stop instead.
+    fn wait_for_job(&self) -> bool {
+        let mut sleep = self.sleep.lock().unwrap();
+
+        // Counted before looking, so a submitter either sees this worker
+        // waiting or this worker sees its job
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        if self.bound.is_some() {
+            self.room.notify_all();
+        }
+
+        let found = loop {
+            if self.queued.load(Ordering::SeqCst) > 0 {
+                break true;
+            }
+
+            if sleep.stopping {
+                break false;
+            }
+
+            if sleep.retiring > 0 {
+                sleep.retiring -= 1;
+                break false;
+            }
+
+            sleep = self.wakeup.wait(sleep).unwrap();
+
+            // Seen to, whether it finds the job or goes back to waiting
+            sleep.wakeups = sleep.wakeups.saturating_sub(1);
+        };
+
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+
+        found
+    }
+
+    /// Tells waiting workers to give up, once there's nothing left to do.
+    ///
+    /// `count` of them stop, or every one of them with `None`.
+    fn stop_workers(&self, count: Option<usize>) {
+        let mut sleep = self.sleep.lock().unwrap();
+
+        match count {
+            Some(count) => sleep.retiring += count,
+            None => sleep.stopping = true,
+        }
+
+        self.wakeup.notify_all();
+        self.room.notify_all();
+    }
 }
 
 impl ThreadPool {
@@ -22,88 +314,1378 @@ impl ThreadPool {
     ///
     /// The size is the number of workers in the pool.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// The `new` function will panic if the size is zero.
+    /// Returns `PoolCreationError::NoThreads` if the size is zero, and
+    /// `PoolCreationError::Spawn` if a worker thread can't be started.
     pub fn new(size: usize) -> Result<ThreadPool, PoolCreationError> {
-        match size {
-            0 => Err(PoolCreationError),
-            _ => {
-                let (sender, receiver) = mpsc::channel();
+        ThreadPool::builder().size(size).build()
+    }
 
-                let receiver = Arc::new(Mutex::new(receiver));
+    /// Creates a new ThreadPool whose queue holds at most `bound` jobs.
+    ///
+    /// Once the queue is full, `execute` blocks until a worker takes a job off
+    /// of it. A bound of zero hands each job straight to a worker.
+    pub fn bounded(size: usize, bound: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder().size(size).queue_bound(bound).build()
+    }
+
+    /// Starts configuring a ThreadPool, for when `new` and `bounded` don't
+    /// offer enough control.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size: thread::available_parallelism().map_or(1, |size| size.get()),
+            bound: None,
+            name_prefix: None,
+            stack_size: None,
+            init: None,
+            on_panic: None,
+        }
+    }
 
-                let workers = (0..size)
-                    .map(|_| Worker::new(Arc::clone(&receiver)))
-                    .collect();
+    fn from_builder(builder: ThreadPoolBuilder) -> Result<ThreadPool, PoolCreationError> {
+        match builder.size {
+            0 => Err(PoolCreationError::NoThreads),
+            size => {
+                let shared = Arc::new(Shared {
+                    workers: Mutex::new(Vec::new()),
+                    locals: RwLock::new(Vec::new()),
+                    next_local: AtomicUsize::new(0),
+                    bound: builder.bound,
+                    sleep: Mutex::new(Sleep::default()),
+                    wakeup: Condvar::new(),
+                    room: Condvar::new(),
+                    sleepers: AtomicUsize::new(0),
+                    next_id: AtomicUsize::new(0),
+                    queued: AtomicUsize::new(0),
+                    active: AtomicUsize::new(0),
+                    idle: Condvar::new(),
+                    idle_lock: Mutex::new(()),
+                    name_prefix: builder.name_prefix,
+                    stack_size: builder.stack_size,
+                    init: builder.init,
+                    on_panic: builder.on_panic,
+                    closed: AtomicBool::new(false),
+                    paused: Mutex::new(false),
+                    resumed: Condvar::new(),
+                });
 
-                Ok(ThreadPool { workers, sender })
+                // Whatever did start is shut down again when this is dropped
+                let pool = ThreadPool {
+                    shared,
+                    timer: Mutex::new(None),
+                };
+
+                for _ in 0..size {
+                    Worker::spawn(&pool.shared).map_err(PoolCreationError::Spawn)?;
+                }
+
+                Ok(pool)
             }
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Runs a job on the pool.
+    ///
+    /// Fails if the pool has shut down, in which case the job is dropped.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.execute_job(Box::new(f))
+    }
+
+    /// Runs every job in `jobs` on the pool.
+    ///
+    /// An unbounded pool deals the whole batch out at once, a run of jobs to
+    /// each worker, which saves taking the locks once per job when fanning
+    /// out lots of small ones. A bounded pool takes them one at a time,
+    /// waiting for room like `execute`.
+    pub fn execute_batch<I>(&self, jobs: I) -> Result<(), ExecuteError>
+    where
+        I: IntoIterator<Item = Job>,
+    {
+        self.check_open()?;
+
+        match self.shared.bound {
+            Some(_) => jobs.into_iter().try_for_each(|job| self.execute_job(job)),
+            None => {
+                let jobs: Vec<Job> = jobs.into_iter().collect();
+
+                self.shared
+                    .submit_unbounded(jobs.into_iter())
+                    .map_err(|_| ExecuteError)
+            }
+        }
+    }
+
+    /// Runs a job on the pool, handing it the state of the worker it runs on.
+    ///
+    /// The state is made by the function given to `ThreadPoolBuilder::init`
+    /// when the worker starts, and lasts as long as the worker does.
+    ///
+    /// # Panics
+    ///
+    /// The job panics, on its worker, if the pool wasn't built with a state of
+    /// type `T`.
+    pub fn execute_with_state<T, F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        T: 'static,
+        F: FnOnce(&mut T) + Send + 'static,
+    {
+        self.execute(move || {
+            WORKER_STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let state = state
+                    .as_mut()
+                    .and_then(|state| state.downcast_mut::<T>())
+                    .expect("the worker has no state of the type the job wants");
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+                f(state);
+            })
+        })
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+    fn execute_job(&self, job: Job) -> Result<(), ExecuteError> {
+        self.shared.submit(job)
+    }
 
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+    fn check_open(&self) -> Result<(), ExecuteError> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            Err(ExecuteError)
+        } else {
+            Ok(())
         }
+    }
 
-        println!("Shutting down all workers.");
+    /// Runs a job on the pool once `delay` has passed.
+    ///
+    /// The job joins the back of the queue when its time comes, so it may run
+    /// a little later. Jobs still waiting when the pool shuts down are dropped
+    /// without running.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.with_timer(|timer| timer.schedule(Instant::now() + delay, Box::new(f)))
+    }
+
+    /// Runs a task on the pool every `period`, until it's cancelled through
+    /// the returned handle or the pool shuts down.
+    ///
+    /// The first run is a period from now. Runs never overlap: if the last one
+    /// is still queued or running when the next is due, the next one is
+    /// skipped rather than queued up behind it.
+    pub fn schedule_interval<F>(&self, period: Duration, f: F) -> Result<TaskHandle, ExecuteError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.with_timer(|timer| timer.schedule_every(period, f))
+    }
+
+    /// Calls `f` with the timer, starting it if this is the first time.
+    fn with_timer<T>(&self, f: impl FnOnce(&Timer) -> T) -> Result<T, ExecuteError> {
+        let mut timer = self.timer.lock().unwrap();
+
+        // Checked under the lock, so the timer can't be started again once stopped
+        self.check_open()?;
+
+        let timer = match &mut *timer {
+            Some(timer) => timer,
+            None => {
+                let started = Timer::start(Arc::clone(&self.shared));
+                timer.insert(started.expect("could not start the timer thread"))
+            }
+        };
+
+        Ok(f(timer))
+    }
+
+    /// Runs jobs that can borrow from the caller, waiting for them all to
+    /// finish before returning.
+    ///
+    /// Jobs are given to the pool through the `Scope` passed to `f`, much like
+    /// `std::thread::scope`. If any of them panic, so does this once the rest
+    /// are done. Calling this from one of the pool's own jobs can deadlock if
+    /// every worker ends up waiting.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+
+        // Jobs must be waited on even if `f` panics, since they may borrow from it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        let mut pending = scope.state.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = scope.state.done.wait(pending).unwrap();
+        }
+        drop(pending);
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::SeqCst) => {
+                panic!("a scoped job panicked")
+            }
+            Ok(value) => value,
+        }
+    }
+
+    /// Runs a job on the pool unless its queue is full.
+    ///
+    /// Never blocks, so callers can turn work away instead of waiting. A full
+    /// queue gives the job back inside `TrySendError::Full`. Pools without a
+    /// bound are never full.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), mpsc::TrySendError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Err(mpsc::TrySendError::Disconnected(f));
+        }
+
+        // Kept aside so it can be handed back, as the queue only sees a Job
+        let job = Arc::new(Mutex::new(Some(f)));
+        let queued = Arc::clone(&job);
+        let submitted = self.shared.try_submit(Box::new(move || {
+            if let Some(f) = queued.lock().unwrap().take() {
+                f();
+            }
+        }));
+
+        let give_back = || job.lock().unwrap().take().expect("job was not run");
+
+        match submitted {
+            Ok(()) => Ok(()),
+            Err(SubmitError::Full(_)) => Err(mpsc::TrySendError::Full(give_back())),
+            Err(SubmitError::Closed) => Err(mpsc::TrySendError::Disconnected(give_back())),
+        }
+    }
+
+    /// Blocks until every job queued or running has finished.
+    ///
+    /// The pool keeps running, so more jobs can be given to it afterwards.
+    pub fn join(&self) {
+        let mut guard = self.shared.idle_lock.lock().unwrap();
+
+        while !self.shared.is_idle() {
+            guard = self.shared.idle.wait(guard).unwrap();
+        }
+    }
+
+    /// Stops workers starting any more jobs until `resume` is called.
+    ///
+    /// Jobs already running carry on to the end, and jobs given to the pool
+    /// meanwhile wait in the queue. Joining a paused pool with jobs queued
+    /// blocks until it's resumed. Shutting down resumes the pool, so the
+    /// queued jobs still run.
+    pub fn pause(&self) {
+        *self.shared.paused.lock().unwrap() = true;
+    }
+
+    /// Lets workers start jobs again after `pause`.
+    pub fn resume(&self) {
+        *self.shared.paused.lock().unwrap() = false;
+        self.shared.resumed.notify_all();
+    }
+
+    /// The number of jobs waiting for a worker.
+    pub fn queued_count(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// The number of jobs being run right now.
+    pub fn active_count(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// The number of workers waiting for a job.
+    pub fn idle_count(&self) -> usize {
+        self.size().saturating_sub(self.active_count())
+    }
+
+    /// The number of workers in the pool.
+    pub fn size(&self) -> usize {
+        self.shared.workers.lock().unwrap().len()
+    }
+
+    /// Grows or shrinks the pool to `size` workers.
+    ///
+    /// Workers are let go once the jobs already queued have been run, and this
+    /// blocks until they have.
+    ///
+    /// Fails if the size is zero or a new worker can't be started, in which
+    /// case any that could be are kept.
+    pub fn set_size(&mut self, size: usize) -> Result<(), PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::NoThreads);
+        }
 
-        for worker in &mut self.workers {
-            // println!("Shutting down worker {}", worker.id);
+        let current = self.size();
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        for _ in current..size {
+            Worker::spawn(&self.shared).map_err(PoolCreationError::Spawn)?;
+        }
+
+        if size >= current {
+            return Ok(());
+        }
+
+        let retiring = current - size;
+        self.shared.stop_workers(Some(retiring));
+
+        // Whichever workers run out of jobs first are the ones to join
+        let mut retired = 0;
+        while retired < retiring {
+            let finished: Vec<Worker> = {
+                let mut workers = self.shared.workers.lock().unwrap();
+                let (finished, running) = workers.drain(..).partition(Worker::is_finished);
+                *workers = running;
+
+                finished
+            };
+
+            for mut worker in finished {
+                if let Some(thread) = worker.thread.take() {
+                    thread.join().unwrap();
+                }
+
+                retired += 1;
+            }
+
+            if retired < retiring {
+                thread::sleep(Duration::from_millis(1));
             }
         }
+
+        Ok(())
+    }
+
+    /// Stops the pool once the jobs already queued have run.
+    ///
+    /// Blocks until every worker has finished. Workers whose threads panicked
+    /// along the way give back what they panicked with.
+    pub fn shutdown(mut self) -> Result<(), Vec<Box<dyn Any + Send>>> {
+        let (panics, _) = self.terminate(None);
+
+        if panics.is_empty() {
+            Ok(())
+        } else {
+            Err(panics)
+        }
+    }
+
+    /// Stops the pool, giving up on workers that haven't finished within
+    /// `timeout`.
+    ///
+    /// Workers still busy when time runs out are left to finish on their own,
+    /// and their ids are given back.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Result<(), Vec<usize>> {
+        let (_, unfinished) = self.terminate(Some(Instant::now() + timeout));
+
+        if unfinished.is_empty() {
+            Ok(())
+        } else {
+            Err(unfinished)
+        }
+    }
+
+    /// Tells every worker to stop once the queued jobs have run, and joins
+    /// them, collecting any panics.
+    ///
+    /// With a deadline, workers that haven't finished by then are detached,
+    /// and their ids are returned too.
+    fn terminate(&mut self, deadline: Option<Instant>) -> (Vec<Box<dyn Any + Send>>, Vec<usize>) {
+        // Paused workers leave the queue full, which would hold up the timer
+        self.resume();
+
+        // Nothing should join the queue once the workers are told to stop
+        let mut timer = self.timer.lock().unwrap();
+        self.shared.closed.store(true, Ordering::SeqCst);
+        if let Some(mut timer) = timer.take() {
+            timer.stop();
+        }
+        drop(timer);
+
+        // Taken out so workers dying meanwhile can still get at the list
+        let mut workers = std::mem::take(&mut *self.shared.workers.lock().unwrap());
+
+        self.shared.stop_workers(None);
+
+        if let Some(deadline) = deadline {
+            while Instant::now() < deadline && !workers.iter().all(Worker::is_finished) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let mut panics = Vec::new();
+        let mut unfinished = Vec::new();
+
+        for worker in &mut workers {
+            if deadline.is_some() && !worker.is_finished() {
+                unfinished.push(worker.id);
+                continue;
+            }
+
+            if let Some(Err(payload)) = worker.thread.take().map(thread::JoinHandle::join) {
+                panics.push(payload);
+            }
+        }
+
+        (panics, unfinished)
+    }
+
+    /// Runs a job on the pool and sends back what it returns.
+    ///
+    /// The result arrives on the returned receiver once the job has run. If
+    /// the job never finishes, or the pool has shut down, receiving fails
+    /// instead.
+    pub fn execute_with_result<F, R>(&self, f: F) -> mpsc::Receiver<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        // Failing drops the sender, which the receiver sees
+        let _ = self.execute(move || {
+            // Nobody may be waiting on the result any more
+            let _ = sender.send(f());
+        });
+
+        receiver
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Panicking workers have been logged already
+        let _ = self.terminate(None);
     }
 }
 
 struct Worker {
+    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+    fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
 
-            match message {
-                Message::NewJob(job) => {
-                    job();
-                }
-                Message::Terminate => {
-                    break;
+    /// Starts a worker taking jobs from the pool's queue.
+    fn spawn(shared: &Arc<Shared>) -> io::Result<()> {
+        Worker::start(shared, Arc::default())
+    }
+
+    /// Starts a worker that begins with the jobs in `local`.
+    fn start(shared: &Arc<Shared>, local: Arc<Local>) -> io::Result<()> {
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let sentinel = Sentinel {
+            shared: Arc::clone(shared),
+            id,
+            local: Arc::clone(&local),
+        };
+
+        // Held until the worker is listed, so it's there by the time it runs a job
+        let mut workers = shared.workers.lock().unwrap();
+
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &shared.name_prefix {
+            builder = builder.name(format!("{}-{}", prefix, id));
+        }
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let thread = builder.spawn(move || {
+            if let Some(init) = &sentinel.shared.init {
+                // Not worth replacing the worker over, it would only panic again
+                match panic::catch_unwind(AssertUnwindSafe(|| init())) {
+                    Ok(state) => WORKER_STATE.with(|slot| *slot.borrow_mut() = Some(state)),
+                    Err(payload) => {
+                        log::error!("worker init panicked: {}", panic_message(&*payload))
+                    }
                 }
             }
-        });
 
-        Worker {
+            Worker::run(&sentinel);
+        })?;
+
+        shared.locals.write().unwrap().push((id, local));
+        workers.push(Worker {
+            id,
             thread: Some(thread),
+        });
+
+        Ok(())
+    }
+
+    /// Runs jobs until told to stop.
+    ///
+    /// Jobs come from the worker's own deque first, then from other workers'
+    /// deques, so workers only contend over the jobs they share. With none
+    /// anywhere, the worker waits to be woken for one.
+    fn run(sentinel: &Sentinel) {
+        let shared = &sentinel.shared;
+        let mut misses = 0;
+
+        loop {
+            let mut paused = shared.paused.lock().unwrap();
+            while *paused {
+                paused = shared.resumed.wait(paused).unwrap();
+            }
+            drop(paused);
+
+            let next = sentinel.local.lock().unwrap().pop_front();
+            if let Some(job) = next.or_else(|| shared.steal(sentinel.id)) {
+                misses = 0;
+                Worker::run_job(shared, job);
+                continue;
+            }
+
+            // More jobs tend to follow soon, and going to sleep and being
+            // woken costs more than looking again a few times
+            if misses < IDLE_SPINS {
+                misses += 1;
+                thread::yield_now();
+                continue;
+            }
+
+            misses = 0;
+            if !shared.wait_for_job() {
+                break;
+            }
         }
     }
+
+    fn run_job(shared: &Shared, job: Job) {
+        shared.active.fetch_add(1, Ordering::SeqCst);
+        shared.queued.fetch_sub(1, Ordering::SeqCst);
+
+        if shared.bound.is_some() {
+            let _sleep = shared.sleep.lock().unwrap();
+            shared.room.notify_one();
+        }
+
+        // A panicking job shouldn't take the worker down with it
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+        shared.active.fetch_sub(1, Ordering::SeqCst);
+
+        if shared.is_idle() {
+            // Taking the lock makes sure a waiter is either waiting or yet to look
+            let _guard = shared.idle_lock.lock().unwrap();
+            shared.idle.notify_all();
+        }
+
+        if let Err(payload) = result {
+            log::error!("job panicked: {}", panic_message(&*payload));
+
+            if let Some(on_panic) = &shared.on_panic {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| on_panic(payload))) {
+                    log::error!("panic callback panicked: {}", panic_message(&*payload));
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the worker it belongs to if that worker's thread dies.
+struct Sentinel {
+    shared: Arc<Shared>,
+    id: usize,
+    local: Arc<Local>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            log::error!("worker {} died, starting a replacement", self.id);
+
+            let mut workers = self.shared.workers.lock().unwrap();
+
+            // Once the pool has taken the list to shut down, let the worker go
+            if let Some(index) = workers.iter().position(|worker| worker.id == self.id) {
+                workers.remove(index);
+                drop(workers);
+
+                // The replacement carries on with whatever this one had left
+                match Worker::start(&self.shared, Arc::clone(&self.local)) {
+                    Ok(()) => self.forget(),
+                    Err(err) => log::error!("could not replace worker {}: {}", self.id, err),
+                }
+
+                return;
+            }
+        }
+
+        // Anything left behind stays up for the other workers to steal
+        let mut locals = self.shared.locals.write().unwrap();
+        if self.local.lock().unwrap().is_empty() {
+            locals.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+impl Sentinel {
+    /// Stops jobs being dealt to this worker, or stolen from it.
+    fn forget(&self) {
+        let mut locals = self.shared.locals.write().unwrap();
+        locals.retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Gets the message a panic was started with, if it had one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+/// Gives jobs that borrow from outside it to a pool.
+///
+/// Made by `ThreadPool::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Keeps track of the jobs in a scope that haven't finished.
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panicked: AtomicBool,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Runs a job on the pool, which can borrow anything that outlives the
+    /// scope.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+
+        let state = Arc::clone(&self.state);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+
+            if result.is_err() {
+                state.panicked.store(true, Ordering::SeqCst);
+            }
+
+            let mut pending = state.pending.lock().unwrap();
+            *pending -= 1;
+            if *pending == 0 {
+                state.done.notify_all();
+            }
+        });
+
+        // SAFETY: `ThreadPool::scope` doesn't return until every job given to
+        // the scope has run, so nothing the job borrows goes away before then
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        // The scope borrows the pool, so it can't have shut down
+        self.pool
+            .execute_job(job)
+            .expect("the pool shut down during a scope");
+    }
+}
+
+/// Configures a ThreadPool before it's created.
+///
+/// Made with `ThreadPool::builder`.
+pub struct ThreadPoolBuilder {
+    size: usize,
+    bound: Option<usize>,
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    init: Option<Arc<Init>>,
+    on_panic: Option<Arc<OnPanic>>,
 }
 
+impl ThreadPoolBuilder {
+    /// Sets the number of workers. Defaults to the number of CPUs.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.size = size;
+        self
+    }
+
+    /// Limits the queue to `bound` jobs, as with `ThreadPool::bounded`.
+    pub fn queue_bound(mut self, bound: usize) -> ThreadPoolBuilder {
+        self.bound = Some(bound);
+        self
+    }
+
+    /// Names worker threads `prefix` followed by a dash and their id, as in
+    /// `pool-worker-0`.
+    pub fn name_prefix(mut self, prefix: &str) -> ThreadPoolBuilder {
+        self.name_prefix = Some(String::from(prefix));
+        self
+    }
+
+    /// Sets the stack size of worker threads, in bytes.
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Gives each worker its own state, made by calling `init` as the worker
+    /// starts.
+    ///
+    /// Jobs get at the state through `ThreadPool::execute_with_state`, which
+    /// suits things too costly to make for every job, like connections or
+    /// scratch buffers.
+    pub fn init<T, F>(mut self, init: F) -> ThreadPoolBuilder
+    where
+        T: 'static,
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        self.init = Some(Arc::new(move || Box::new(init()) as Box<dyn Any>));
+        self
+    }
+
+    /// Calls `on_panic` with what a job panicked with, whenever one does.
+    ///
+    /// It runs on the worker the job panicked on, after the panic has been
+    /// logged. Panics in `on_panic` itself are logged and otherwise ignored.
+    pub fn on_panic<F>(mut self, on_panic: F) -> ThreadPoolBuilder
+    where
+        F: Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Arc::new(on_panic));
+        self
+    }
+
+    /// Creates the pool and starts its workers.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::from_builder(self)
+    }
+}
+
+/// Returned when a job is given to a pool that has shut down.
 #[derive(Debug)]
-pub struct PoolCreationError;
+pub struct ExecuteError;
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The pool has shut down")
+    }
+}
+
+impl Error for ExecuteError {}
+
+#[derive(Debug)]
+pub enum PoolCreationError {
+    NoThreads,
+    /// A worker thread couldn't be started.
+    Spawn(io::Error),
+}
 
 impl fmt::Display for PoolCreationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Pool cannot be created with 0 threads")
+        match self {
+            PoolCreationError::NoThreads => write!(f, "Pool cannot be created with 0 threads"),
+            PoolCreationError::Spawn(err) => write!(f, "Could not start a worker: {}", err),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::NoThreads => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
     }
 }
 
-impl Error for PoolCreationError {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn jobs_send_back_results() {
+        let pool = ThreadPool::new(3).unwrap();
+
+        let results: Vec<_> = (1..=10)
+            .map(|n| pool.execute_with_result(move || n * n))
+            .collect();
+
+        let sum: i32 = results.iter().map(|result| result.recv().unwrap()).sum();
+        assert_eq!(sum, 385);
+    }
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let pool = ThreadPool::new(1).unwrap();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        let result = pool.execute_with_result(|| "still working");
+
+        assert_eq!(result.recv().unwrap(), "still working");
+    }
+
+    #[test]
+    fn pools_can_be_resized() {
+        let mut pool = ThreadPool::new(2).unwrap();
+
+        pool.set_size(4).unwrap();
+        assert_eq!(pool.size(), 4);
+
+        // Jobs already queued still run when the pool shrinks
+        let results: Vec<_> = (0..8)
+            .map(|n| {
+                pool.execute_with_result(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    n
+                })
+            })
+            .collect();
+
+        pool.set_size(1).unwrap();
+        assert_eq!(pool.size(), 1);
+
+        let sum: i32 = results.iter().map(|result| result.recv().unwrap()).sum();
+        assert_eq!(sum, 28);
+        assert_eq!(pool.execute_with_result(|| 1).recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        let pool = ThreadPool::new(2).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..10 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        assert!(pool.shutdown().is_ok());
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn bounded_queues_block_when_full() {
+        let pool = Arc::new(ThreadPool::bounded(1, 1).unwrap());
+        let (release, gate) = mpsc::channel::<()>();
+
+        // Keep the only worker busy, then fill the queue
+        pool.execute(move || {
+            let _ = gate.recv();
+        })
+        .unwrap();
+        pool.execute(|| {}).unwrap();
+
+        let queued = Arc::new(AtomicBool::new(false));
+        let producer = thread::spawn({
+            let (pool, queued) = (Arc::clone(&pool), Arc::clone(&queued));
+            move || {
+                pool.execute(|| {}).unwrap();
+                queued.store(true, Ordering::SeqCst);
+            }
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!queued.load(Ordering::SeqCst));
+
+        release.send(()).unwrap();
+        producer.join().unwrap();
+        assert!(queued.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn try_execute_gives_jobs_back_when_full() {
+        let pool = ThreadPool::bounded(1, 1).unwrap();
+        let (release, gate) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = gate.recv();
+        })
+        .unwrap();
+        running.recv().unwrap();
+
+        assert!(pool.try_execute(|| {}).is_ok());
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        let job = match pool.try_execute(move || flag.store(true, Ordering::SeqCst)) {
+            Err(mpsc::TrySendError::Full(job)) => job,
+            _ => panic!("queue should be full"),
+        };
+
+        // The caller still has the job to run itself
+        job();
+        assert!(ran.load(Ordering::SeqCst));
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn counts_follow_jobs() {
+        let pool = ThreadPool::new(1).unwrap();
+        let (release, gate) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        assert_eq!(pool.idle_count(), 1);
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = gate.recv();
+        })
+        .unwrap();
+        running.recv().unwrap();
+        let last = pool.execute_with_result(|| ());
+
+        assert_eq!(pool.active_count(), 1);
+        assert_eq!(pool.queued_count(), 1);
+        assert_eq!(pool.idle_count(), 0);
+
+        release.send(()).unwrap();
+        last.recv().unwrap();
+
+        // The worker sends the result before it's done with the job
+        while pool.active_count() > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pool.queued_count(), 0);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn workers_are_named() {
+        let pool = ThreadPool::builder()
+            .size(2)
+            .name_prefix("pool-worker")
+            .stack_size(256 * 1024)
+            .build()
+            .unwrap();
+
+        let mut names: Vec<_> = (0..2)
+            .map(|_| pool.execute_with_result(|| thread::current().name().map(String::from)))
+            .map(|name| name.recv().unwrap().unwrap())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        assert!(names.iter().all(|name| name.starts_with("pool-worker-")));
+        assert!(matches!(
+            ThreadPool::builder().size(0).build(),
+            Err(PoolCreationError::NoThreads)
+        ));
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_stuck_workers() {
+        let pool = ThreadPool::new(2).unwrap();
+        let (release, gate) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = gate.recv();
+        })
+        .unwrap();
+        running.recv().unwrap();
+
+        let start = Instant::now();
+        let unfinished = pool
+            .shutdown_timeout(Duration::from_millis(50))
+            .unwrap_err();
+
+        assert_eq!(unfinished.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn shutdown_timeout_is_not_held_up_by_the_timer() {
+        let pool = ThreadPool::bounded(1, 1).unwrap();
+        let (release, gate) = mpsc::channel::<()>();
+        let (started, running) = mpsc::channel();
+
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = gate.recv();
+        })
+        .unwrap();
+        running.recv().unwrap();
+        pool.execute(|| {}).unwrap();
+
+        // Comes due with the queue full, so the timer waits for room
+        pool.execute_after(Duration::from_millis(1), || {}).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let start = Instant::now();
+        assert!(pool.shutdown_timeout(Duration::from_millis(50)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn join_waits_for_every_job() {
+        let pool = ThreadPool::new(3).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        for round in 1..=2 {
+            for _ in 0..20 {
+                let count = Arc::clone(&count);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(1));
+                    count.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+
+            pool.join();
+            assert_eq!(count.load(Ordering::SeqCst), 20 * round);
+        }
+    }
+
+    #[test]
+    fn batches_run_like_single_jobs() {
+        let pool = ThreadPool::new(4).unwrap();
+        let single = Arc::new(AtomicUsize::new(0));
+        let batched = Arc::new(AtomicUsize::new(0));
+
+        for i in 1..=1000 {
+            let single = Arc::clone(&single);
+            pool.execute(move || {
+                single.fetch_add(i, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        pool.execute_batch((1..=1000).map(|i| {
+            let batched = Arc::clone(&batched);
+            Box::new(move || {
+                batched.fetch_add(i, Ordering::SeqCst);
+            }) as Job
+        }))
+        .unwrap();
+
+        pool.join();
+        assert_eq!(pool.queued_count(), 0);
+        assert_eq!(batched.load(Ordering::SeqCst), 500_500);
+        assert_eq!(single.load(Ordering::SeqCst), 500_500);
+    }
+
+    #[test]
+    fn batches_are_dealt_out_at_once() {
+        let pool = ThreadPool::new(4).unwrap();
+        let sum = Arc::new(AtomicUsize::new(0));
+
+        // Paused so the whole batch is still waiting when it's looked at
+        pool.pause();
+        pool.execute_batch((1..=10).map(|i| {
+            let sum = Arc::clone(&sum);
+            Box::new(move || {
+                sum.fetch_add(i, Ordering::SeqCst);
+            }) as Job
+        }))
+        .unwrap();
+
+        assert_eq!(pool.queued_count(), 10);
+        let dealt: Vec<usize> = {
+            let locals = pool.shared.locals.read().unwrap();
+            locals
+                .iter()
+                .map(|(_, local)| local.lock().unwrap().len())
+                .collect()
+        };
+        assert_eq!(dealt, [3, 3, 2, 2]);
+
+        pool.resume();
+        pool.join();
+        assert_eq!(pool.queued_count(), 0);
+        assert_eq!(sum.load(Ordering::SeqCst), 55);
+    }
+
+    #[test]
+    fn workers_keep_their_own_state() {
+        let pool = ThreadPool::builder()
+            .size(3)
+            .init(|| 0_usize)
+            .build()
+            .unwrap();
+        let (sender, receiver) = mpsc::channel();
+
+        for _ in 0..60 {
+            let sender = sender.clone();
+            pool.execute_with_state(move |count: &mut usize| {
+                *count += 1;
+                sender.send((thread::current().id(), *count)).unwrap();
+            })
+            .unwrap();
+        }
+        drop(sender);
+
+        let mut counts = HashMap::new();
+        for (worker, count) in receiver {
+            counts.entry(worker).or_insert_with(Vec::new).push(count);
+        }
+
+        // Each worker counts up from one on its own
+        assert!(counts.len() <= 3);
+        for seen in counts.values() {
+            assert_eq!(*seen, (1..=seen.len()).collect::<Vec<_>>());
+        }
+        assert_eq!(counts.values().map(Vec::len).sum::<usize>(), 60);
+    }
+
+    #[test]
+    fn jobs_are_refused_once_shut_down() {
+        let mut pool = ThreadPool::new(2).unwrap();
+
+        // What shutting down or dropping the pool does before letting it go
+        pool.terminate(None);
+
+        assert!(pool.execute(|| {}).is_err());
+        assert!(pool.execute_after(Duration::from_millis(1), || {}).is_err());
+        assert!(matches!(
+            pool.try_execute(|| {}),
+            Err(mpsc::TrySendError::Disconnected(_))
+        ));
+        assert!(pool.execute_with_result(|| ()).recv().is_err());
+        assert_eq!(pool.queued_count(), 0);
+    }
+
+    #[test]
+    fn idle_workers_steal_dealt_jobs() {
+        for pool in [ThreadPool::new(2), ThreadPool::bounded(2, 16)] {
+            let pool = pool.unwrap();
+            let (release, gate) = mpsc::channel::<()>();
+            let (done, finished) = mpsc::channel();
+
+            // Paused so every job is dealt out before either worker starts one
+            pool.pause();
+            pool.execute(move || {
+                let _ = gate.recv();
+            })
+            .unwrap();
+            for _ in 0..10 {
+                let done = done.clone();
+                pool.execute(move || done.send(()).unwrap()).unwrap();
+            }
+
+            let dealt: Vec<usize> = {
+                let locals = pool.shared.locals.read().unwrap();
+                locals
+                    .iter()
+                    .map(|(_, local)| local.lock().unwrap().len())
+                    .collect()
+            };
+            assert_eq!(dealt, [6, 5]);
+            pool.resume();
+
+            // The first job holds up its worker, so the other has to steal the
+            // jobs dealt to it
+            for _ in 0..10 {
+                finished.recv_timeout(Duration::from_secs(5)).unwrap();
+            }
+
+            release.send(()).unwrap();
+            pool.join();
+        }
+    }
+
+    #[test]
+    fn many_small_jobs_all_run() {
+        let pool = Arc::new(ThreadPool::new(4).unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let submitters: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = Arc::clone(&pool);
+                let count = Arc::clone(&count);
+
+                thread::spawn(move || {
+                    for _ in 0..25_000 {
+                        let count = Arc::clone(&count);
+                        pool.execute(move || {
+                            count.fetch_add(1, Ordering::Relaxed);
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for submitter in submitters {
+            submitter.join().unwrap();
+        }
+
+        pool.join();
+        assert_eq!(count.load(Ordering::SeqCst), 100_000);
+        assert_eq!(pool.queued_count(), 0);
+    }
+
+    #[test]
+    fn paused_pools_hold_jobs_until_resumed() {
+        let pool = ThreadPool::new(2).unwrap();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        pool.pause();
+
+        let flag = Arc::clone(&ran);
+        pool.execute(move || flag.store(true, Ordering::SeqCst))
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(pool.queued_count(), 1);
+
+        pool.resume();
+        pool.join();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panics_are_passed_to_the_callback() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        let pool = ThreadPool::builder()
+            .size(1)
+            .on_panic(move |payload| {
+                let message = panic_message(&*payload).to_string();
+                sender.lock().unwrap().send(message).unwrap();
+                panic!("the callback panics too");
+            })
+            .build()
+            .unwrap();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "job failed"
+        );
+
+        // The callback panicking doesn't take the worker with it
+        assert_eq!(pool.execute_with_result(|| 1).recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn scoped_jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(3).unwrap();
+        let mut numbers = [1; 12];
+
+        pool.scope(|scope| {
+            for (i, chunk) in numbers.chunks_mut(4).enumerate() {
+                scope.execute(move || {
+                    for number in chunk {
+                        *number *= i + 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(numbers, [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn delayed_jobs_wait_their_turn() {
+        let pool = ThreadPool::new(2).unwrap();
+        let (sender, ran) = mpsc::channel();
+
+        let start = Instant::now();
+        pool.execute_after(Duration::from_millis(50), move || {
+            sender.send(Instant::now()).unwrap();
+        })
+        .unwrap();
+
+        assert!(ran.recv_timeout(Duration::from_millis(20)).is_err());
+        let at = ran.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(at - start >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn shutdown_drops_delayed_jobs() {
+        let pool = ThreadPool::new(1).unwrap();
+        let ran = Arc::new(AtomicBool::new(false));
+
+        let flag = Arc::clone(&ran);
+        pool.execute_after(Duration::from_secs(60), move || {
+            flag.store(true, Ordering::SeqCst);
+        })
+        .unwrap();
+
+        let start = Instant::now();
+        assert!(pool.shutdown().is_ok());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn interval_tasks_repeat_until_cancelled() {
+        let pool = ThreadPool::new(2).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let runs = Arc::clone(&count);
+        let task = pool
+            .schedule_interval(Duration::from_millis(10), move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        thread::sleep(Duration::from_millis(200));
+        task.cancel();
+        pool.join();
+
+        let counted = count.load(Ordering::SeqCst);
+        assert!(counted >= 5, "only ran {} times", counted);
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::SeqCst), counted);
+    }
+
+    /// A panic payload that panics again when dropped, which gets past
+    /// catching the panic and kills the worker.
+    struct Explosive;
+
+    impl Drop for Explosive {
+        fn drop(&mut self) {
+            panic!("worker killed");
+        }
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let pool = ThreadPool::new(1).unwrap();
+        let first = pool.shared.workers.lock().unwrap()[0].id;
+
+        pool.execute(|| panic::panic_any(Explosive)).unwrap();
+        let result = pool.execute_with_result(|| "still working");
+
+        assert_eq!(result.recv().unwrap(), "still working");
+
+        let workers = pool.shared.workers.lock().unwrap();
+        assert_eq!(workers.len(), 1);
+        assert_ne!(workers[0].id, first);
+    }
+}
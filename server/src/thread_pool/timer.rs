@@ -0,0 +1,252 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{Job, Shared, SubmitError};
+
+/// How often the timer tries again to queue a job while the queue is full.
+const SUBMIT_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A thread that puts jobs on the pool's queue once they're due.
+pub(super) struct Timer {
+    state: Arc<TimerState>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+struct TimerState {
+    queue: Mutex<TimerQueue>,
+    /// Signalled when a job is added or the timer is stopped.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct TimerQueue {
+    jobs: BinaryHeap<Delayed>,
+    /// Breaks ties so jobs due at the same time run in the order given.
+    next_seq: u64,
+    stopped: bool,
+}
+
+/// A job waiting for its time to come.
+struct Delayed {
+    at: Instant,
+    seq: u64,
+    task: Task,
+}
+
+enum Task {
+    Once(Job),
+    Every(Arc<Recurring>),
+}
+
+/// A task run over and over.
+struct Recurring {
+    run: Box<dyn Fn() + Send + Sync>,
+    period: Duration,
+    /// Set while a run is queued or running, so runs never overlap.
+    busy: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+/// Lets the owner of a recurring task stop it.
+///
+/// Dropping the handle leaves the task running.
+pub struct TaskHandle {
+    task: Arc<Recurring>,
+}
+
+impl TaskHandle {
+    /// Stops the task from running again.
+    ///
+    /// A run that's already started carries on to the end.
+    pub fn cancel(&self) {
+        self.task.cancelled.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+impl Timer {
+    pub(super) fn start(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state = Arc::new(TimerState {
+            queue: Mutex::new(TimerQueue::default()),
+            changed: Condvar::new(),
+        });
+
+        let timer_state = Arc::clone(&state);
+        let thread = thread::Builder::new()
+            .name(String::from("pool-timer"))
+            .spawn(move || run(&timer_state, &shared))?;
+
+        Ok(Timer {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// Puts `job` on the pool's queue once `at` comes.
+    pub(super) fn schedule(&self, at: Instant, job: Job) {
+        self.add(at, Task::Once(job));
+    }
+
+    /// Puts `run` on the pool's queue every `period`, starting a period from
+    /// now.
+    pub(super) fn schedule_every<F>(&self, period: Duration, run: F) -> TaskHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let task = Arc::new(Recurring {
+            run: Box::new(run),
+            period,
+            busy: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
+
+        self.add(Instant::now() + period, Task::Every(Arc::clone(&task)));
+
+        TaskHandle { task }
+    }
+
+    fn add(&self, at: Instant, task: Task) {
+        let mut queue = self.state.queue.lock().unwrap();
+
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.jobs.push(Delayed { at, seq, task });
+
+        self.state.changed.notify_one();
+    }
+
+    /// Stops the timer, dropping any jobs that aren't due yet.
+    pub(super) fn stop(&mut self) {
+        self.state.queue.lock().unwrap().stopped = true;
+        self.state.changed.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        self.state.queue.lock().unwrap().jobs.clear();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(state: &TimerState, shared: &Shared) {
+    let mut queue = state.queue.lock().unwrap();
+
+    while !queue.stopped {
+        let now = Instant::now();
+
+        match queue.jobs.peek().map(|delayed| delayed.at) {
+            None => queue = state.changed.wait(queue).unwrap(),
+            Some(at) if at > now => {
+                queue = state.changed.wait_timeout(queue, at - now).unwrap().0;
+            }
+            Some(_) => {
+                let delayed = queue.jobs.pop().unwrap();
+
+                let job = match delayed.task {
+                    Task::Once(job) => job,
+                    Task::Every(task) => {
+                        if task.cancelled.load(atomic::Ordering::SeqCst) {
+                            continue;
+                        }
+
+                        // Times missed while the timer was held up are skipped
+                        let mut next = delayed.at + task.period;
+                        while next <= now {
+                            next += task.period;
+                        }
+
+                        let seq = queue.next_seq;
+                        queue.next_seq += 1;
+                        queue.jobs.push(Delayed {
+                            at: next,
+                            seq,
+                            task: Task::Every(Arc::clone(&task)),
+                        });
+
+                        // Skip this run if the last one hasn't finished
+                        if task.busy.swap(true, atomic::Ordering::SeqCst) {
+                            continue;
+                        }
+
+                        Box::new(move || {
+                            let _done = Done(&task.busy);
+                            (task.run)();
+                        })
+                    }
+                };
+
+                // Submitting can wait on a full queue, so don't hold up scheduling
+                drop(queue);
+                if !submit(state, shared, job) {
+                    return;
+                }
+                queue = state.queue.lock().unwrap();
+            }
+        }
+    }
+}
+
+/// Puts a job on the pool's queue, waiting for room if it's full.
+///
+/// Gives up once the timer is stopped, so a full queue can't keep it from
+/// shutting down. Returns whether the job was queued.
+fn submit(state: &TimerState, shared: &Shared, mut job: Job) -> bool {
+    loop {
+        match shared.try_submit(job) {
+            Ok(()) => return true,
+            Err(SubmitError::Full(unsent)) => {
+                if state.queue.lock().unwrap().stopped {
+                    return false;
+                }
+
+                job = unsent;
+                thread::sleep(SUBMIT_RETRY_INTERVAL);
+            }
+            Err(SubmitError::Closed) => return false,
+        }
+    }
+}
+
+/// Clears a recurring task's busy flag when its run ends, even by panicking.
+struct Done<'a>(&'a AtomicBool);
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+// Ordered so the heap gives back the job due soonest first
+impl Ord for Delayed {
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
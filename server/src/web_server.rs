@@ -1,183 +1,3731 @@
-use http::{Method, Request, Response, Version};
+use flate2::{write::GzEncoder, Compression};
+use http::{
+    header::{
+        HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, ALLOW, CONNECTION, CONTENT_ENCODING,
+        CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, DATE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, LOCATION, RANGE, RETRY_AFTER, SERVER, TRANSFER_ENCODING, VARY,
+    },
+    Method, Request, Response, StatusCode, Version,
+};
 use lazy_static::lazy_static;
 use regex::bytes::Regex;
 use std::{
     collections::HashMap,
     error::Error,
-    fs,
-    io::prelude::*,
-    net::{TcpListener, TcpStream},
-    sync::{Arc, RwLock},
+    fmt, fs,
+    io::{self, prelude::*, BufReader},
+    net::{Shutdown, SocketAddr, TcpListener},
+    ops,
+    panic::{self, AssertUnwindSafe},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{self, PoolCreationError, ThreadPool};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+mod builder;
+mod connections;
+mod cookie;
+mod cors;
+mod file_cache;
+mod http_date;
+mod ip_filter;
+mod metrics;
+mod middleware;
+mod multipart;
+mod range;
+mod rate_limit;
+mod request;
+mod response;
+mod route_pattern;
+mod site;
+mod socket;
+
+use connections::Connections;
+use file_cache::FileCache;
+use http_date::{format_http_date, format_log_date, parse_http_date};
+use ip_filter::IpFilter;
+use metrics::Metrics;
+use range::{parse_range, ByteRange};
+use rate_limit::RateLimiter;
+use socket::{Listener, Socket};
+
+pub use builder::WebServerBuilder;
+pub use cookie::{parse_cookies, InvalidCookie, SameSite, SetCookie};
+pub use cors::Cors;
+pub use ip_filter::InvalidIpRange;
+pub use middleware::{Logger, Middleware, Next};
+pub use multipart::{parse_multipart, MultipartError, MultipartPart};
+pub use request::{parse_form, query_params};
+pub use response::json_response;
+pub use route_pattern::PathParams;
+pub use site::Site;
+
+use route_pattern::RoutePattern;
+use site::Sites;
 
 /// Static routing is looked up from a hashmap.
 type Routes = HashMap<String, String>;
 
-/// A very simple multi-threaded web server with static routing.
+/// Computes a response on the fly.
+///
+/// The client's address is in the request's extensions as a `SocketAddr`,
+/// unless it couldn't be told, as with Unix domain sockets.
+pub type Handler = Box<dyn Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync>;
+
+/// Everything a request can be routed to.
+struct Router {
+    files: Routes,
+    /// Kept behind an `Arc` so a handler can run without holding the lock.
+    handlers: HashMap<String, Arc<Handler>>,
+    /// Handlers for paths that answer each method differently.
+    method_handlers: HashMap<String, HashMap<Method, Arc<Handler>>>,
+    /// Handlers for routes with named segments or wildcards.
+    patterns: Vec<(RoutePattern, Arc<Handler>)>,
+    /// Handlers for routes matched by regex, tried in the order they were added.
+    regexes: Vec<(regex::Regex, Arc<Handler>)>,
+    /// URL prefixes, without a trailing slash, mapped onto directories.
+    dirs: Vec<(String, PathBuf)>,
+    redirects: HashMap<String, Redirect>,
+}
+
+/// Where a redirected path sends clients.
+struct Redirect {
+    to: String,
+    permanent: bool,
+}
+
+/// Where a path inside a served directory points on disk.
+enum DirFile {
+    Found(PathBuf),
+    /// The path tried to escape the directory.
+    Forbidden,
+}
+
+impl Router {
+    fn new(files: Routes) -> Router {
+        Router {
+            files,
+            handlers: HashMap::new(),
+            method_handlers: HashMap::new(),
+            patterns: Vec::new(),
+            regexes: Vec::new(),
+            dirs: Vec::new(),
+            redirects: HashMap::new(),
+        }
+    }
+
+    /// Finds the handler for a path, along with anything its route captured.
+    ///
+    /// Exact routes win, then the most specific matching pattern, then the
+    /// first matching regex.
+    fn handler(&self, path: &str) -> Option<(Arc<Handler>, Option<PathParams>)> {
+        if let Some(handler) = self.handlers.get(path) {
+            return Some((Arc::clone(handler), None));
+        }
+
+        let pattern = self
+            .patterns
+            .iter()
+            .filter_map(|(pattern, handler)| Some((pattern, handler, pattern.matches(path)?)))
+            .max_by_key(|(pattern, _, _)| pattern.specificity());
+
+        if let Some((_, handler, params)) = pattern {
+            return Some((Arc::clone(handler), Some(params)));
+        }
+
+        self.regexes.iter().find_map(|(regex, handler)| {
+            let captures = regex.captures(path)?;
+
+            let params: HashMap<String, String> = regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    Some((
+                        String::from(name),
+                        String::from(captures.name(name)?.as_str()),
+                    ))
+                })
+                .collect();
+
+            Some((Arc::clone(handler), Some(PathParams::from(params))))
+        })
+    }
+
+    /// Whether a path is routed to something exactly, rather than by falling
+    /// under a served directory or matching a regex.
+    fn is_routed(&self, path: &str) -> bool {
+        self.method_handlers.contains_key(path)
+            || self.handlers.contains_key(path)
+            || self.redirects.contains_key(path)
+            || self.files.contains_key(path)
+            || self
+                .patterns
+                .iter()
+                .any(|(pattern, _)| pattern.matches(path).is_some())
+    }
+
+    /// Finds the handler registered for a method on a path.
+    ///
+    /// HEAD requests are answered by the GET handler. If the path has handlers
+    /// but none for the method, the methods it does allow are given instead,
+    /// ready for an `Allow` header.
+    fn method_handler(&self, path: &str, method: &Method) -> Option<Result<Arc<Handler>, String>> {
+        let handlers = self.method_handlers.get(path)?;
+
+        let method = if *method == Method::HEAD && !handlers.contains_key(method) {
+            &Method::GET
+        } else {
+            method
+        };
+
+        if let Some(handler) = handlers.get(method) {
+            return Some(Ok(Arc::clone(handler)));
+        }
+
+        let mut allowed: Vec<&str> = handlers.keys().map(Method::as_str).collect();
+        if handlers.contains_key(&Method::GET) && !handlers.contains_key(&Method::HEAD) {
+            allowed.push(Method::HEAD.as_str());
+        }
+        allowed.sort_unstable();
+
+        Some(Err(allowed.join(", ")))
+    }
+
+    /// Finds the file a path maps to in the most specific served directory.
+    ///
+    /// Paths naming a directory map to its `index` file.
+    fn dir_file(&self, path: &str, index: &str) -> Option<DirFile> {
+        let (prefix, root) = self
+            .dirs
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())?;
+
+        let rest = &path[prefix.len()..];
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+
+        // Only plain names are allowed, so nothing can climb out of the root
+        let mut file = root.clone();
+
+        for component in Path::new(rest).components() {
+            match component {
+                Component::Normal(name) => file.push(name),
+                Component::CurDir => {}
+                _ => return Some(DirFile::Forbidden),
+            }
+        }
+
+        if file.is_dir() {
+            file.push(index);
+        }
+
+        // A symlink inside the root could still point outside of it
+        if let (Ok(root), Ok(resolved)) = (root.canonicalize(), file.canonicalize()) {
+            if !resolved.starts_with(root) {
+                return Some(DirFile::Forbidden);
+            }
+        }
+
+        Some(DirFile::Found(file))
+    }
+}
+
+/// How long handlers get to wind up once their connections are cut off at
+/// shutdown, however little of the grace period is left.
+const CUT_OFF_LINGER: Duration = Duration::from_millis(100);
+
+/// Files at least this big are streamed instead of being read into memory.
+const STREAM_MIN_BYTES: u64 = 1024 * 1024;
+
+/// How much of a streamed file is sent in each chunk.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Bodies smaller than this aren't worth gzipping.
+const GZIP_MIN_BYTES: usize = 1024;
+
+/// How long the accept loop sleeps between checks for new connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait on a client being hung up on to finish sending.
+const LINGER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The longest a client being hung up on gets to finish sending, in all.
+const LINGER_LIMIT: Duration = Duration::from_secs(1);
+
+/// How long the accept loop spends writing to a client it's turning away.
+const REFUSAL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The body sent for a 404 when the not found page can't be read.
+const DEFAULT_NOT_FOUND_BODY: &str = "404 Not Found";
+
+/// What to do with a request whose path only matches a route once a trailing
+/// slash is added or taken away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Paths have to match exactly, so `/about/` is a different route to
+    /// `/about`.
+    Strict,
+    /// Send the client to the path of the route with a 301.
+    Redirect,
+    /// Answer as if the path of the route had been asked for.
+    Merge,
+}
+
+/// What to do with a request whose path has empty or `.` segments, as in
+/// `/a//b` or `/a/./b`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathNormalization {
+    /// Route the path exactly as it was sent.
+    Off,
+    /// Send the client to the normalized path with a 301.
+    Redirect,
+    /// Answer as if the normalized path had been asked for.
+    Merge,
+}
+
+/// Settings shared with every connection.
+#[derive(Clone)]
+struct Config {
+    /// Files served in place of the usual body for error statuses.
+    error_pages: HashMap<StatusCode, String>,
+    index_file: String,
+    max_requests_per_connection: usize,
+    read_timeout: Option<Duration>,
+    max_request_bytes: usize,
+    max_headers: usize,
+    max_header_bytes: usize,
+    buffer_size: usize,
+    server_name: String,
+    middleware: Vec<Arc<dyn Middleware>>,
+    access_log: bool,
+    strict_hosts: bool,
+    trailing_slash: TrailingSlash,
+    path_normalization: PathNormalization,
+    /// Shared by every connection so limits hold across them.
+    rate_limit: Option<Arc<RateLimiter>>,
+    ip_filter: IpFilter,
+    metrics: Arc<Metrics>,
+    shutdown_grace: Duration,
+    max_connections: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            error_pages: vec![(StatusCode::NOT_FOUND, String::from("404.html"))]
+                .into_iter()
+                .collect(),
+            index_file: String::from("index.html"),
+            max_requests_per_connection: 100,
+            read_timeout: Some(Duration::from_secs(30)),
+            max_request_bytes: 1024 * 1024,
+            max_headers: 100,
+            max_header_bytes: 8 * 1024,
+            buffer_size: 8 * 1024,
+            server_name: String::from("rust-playground/0.1"),
+            middleware: Vec::new(),
+            access_log: true,
+            strict_hosts: false,
+            trailing_slash: TrailingSlash::Strict,
+            path_normalization: PathNormalization::Merge,
+            rate_limit: None,
+            ip_filter: IpFilter::default(),
+            metrics: Arc::new(Metrics::default()),
+            shutdown_grace: Duration::from_secs(10),
+            max_connections: None,
+        }
+    }
+}
+
+/// A very simple multi-threaded web server with static and dynamic routing.
 pub struct WebServer {
     thread_limit: usize,
-    routes: Arc<RwLock<Routes>>,
+    sites: Arc<Sites>,
+    config: Config,
+    cache: Arc<FileCache>,
+    stopped: Arc<AtomicBool>,
+    connections: Arc<Connections>,
 }
 
 impl WebServer {
     /// Creates a new web server.
     ///
-    /// Routes cannot be changed once the server is started
+    /// Missing pages are served from `404.html`.
     pub fn new(thread_limit: usize, routes: Routes) -> WebServer {
-        let routes = Arc::new(RwLock::new(routes));
+        let sites = Arc::new(Sites::new(routes));
+
+        let config = Config::default();
 
         WebServer {
             thread_limit,
-            routes,
+            sites,
+            config,
+            cache: Arc::new(FileCache::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+            connections: Arc::new(Connections::default()),
         }
     }
 
+    /// Sets the file served when no route matches.
+    pub fn set_not_found_page(&mut self, path: &str) {
+        self.set_error_page(StatusCode::NOT_FOUND, path);
+    }
+
+    /// Sets the file served when the server answers with an error `status`,
+    /// like a 403 or 500.
+    ///
+    /// Responses computed by handlers are left alone. If the file can't be
+    /// read, a short built-in body is sent instead.
+    pub fn set_error_page(&mut self, status: StatusCode, path: &str) {
+        self.config.error_pages.insert(status, String::from(path));
+    }
+
+    /// Sets the file served for requests naming a directory in a served
+    /// directory.
+    ///
+    /// Defaults to `index.html`.
+    pub fn set_index_file(&mut self, name: &str) {
+        self.config.index_file = String::from(name);
+    }
+
+    /// Sets how many requests a client may send over one connection before
+    /// it is closed.
+    ///
+    /// Defaults to 100. A limit of 1 turns off keep-alive.
+    pub fn set_max_requests_per_connection(&mut self, max: usize) {
+        self.config.max_requests_per_connection = max;
+    }
+
+    /// Sets how long to wait on a quiet client before giving up on it.
+    ///
+    /// Defaults to 30 seconds. `None` waits forever, which lets a slow client
+    /// hold a worker hostage.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.config.read_timeout = timeout;
+    }
+
+    /// Sets the largest request, headers and body together, that will be read.
+    ///
+    /// Defaults to 1 MiB. Anything bigger is answered with a 413.
+    pub fn set_max_request_bytes(&mut self, max: usize) {
+        self.config.max_request_bytes = max;
+    }
+
+    /// Sets how many headers a request may have, and how long each may be.
+    ///
+    /// Defaults to 100 headers of 8 KiB each. Requests over either limit are
+    /// answered with a 431.
+    pub fn set_header_limits(&mut self, max_headers: usize, max_header_bytes: usize) {
+        self.config.max_headers = max_headers;
+        self.config.max_header_bytes = max_header_bytes;
+    }
+
+    /// Sets how many bytes are read off a connection at a time.
+    ///
+    /// Defaults to 8 KiB. Bigger buffers mean fewer reads for large uploads,
+    /// but every open connection holds onto one, so they cost memory.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.config.buffer_size = size;
+    }
+
+    /// Sets the `Server` header sent with every response.
+    ///
+    /// Defaults to `rust-playground/0.1`.
+    pub fn set_server_name(&mut self, name: &str) {
+        self.config.server_name = String::from(name);
+    }
+
+    /// Turns the access log on or off.
+    ///
+    /// When on, a line like the common log format is logged at info level
+    /// under the `access` target for every request. Defaults to on. Nothing
+    /// shows up unless the application sets up a `log` backend.
+    pub fn set_access_log(&mut self, enabled: bool) {
+        self.config.access_log = enabled;
+    }
+
+    /// Sets whether requests for hosts without routes of their own are
+    /// refused with a 404 instead of getting the default host's routes.
+    ///
+    /// Defaults to off.
+    pub fn set_strict_hosts(&mut self, strict: bool) {
+        self.config.strict_hosts = strict;
+    }
+
+    /// Sets how a path that's only a trailing slash off from a route is
+    /// treated.
+    ///
+    /// Defaults to `TrailingSlash::Strict`.
+    pub fn set_trailing_slash(&mut self, mode: TrailingSlash) {
+        self.config.trailing_slash = mode;
+    }
+
+    /// Sets how paths with empty or `.` segments are treated.
+    ///
+    /// Defaults to `PathNormalization::Merge`, so `/a//b` and `/a/./b` are
+    /// both answered as `/a/b`.
+    pub fn set_path_normalization(&mut self, mode: PathNormalization) {
+        self.config.path_normalization = mode;
+    }
+
+    /// Limits each client IP to `limit` requests in any `window` of time.
+    ///
+    /// Requests over the limit are answered with a 429 and a `Retry-After`
+    /// saying how long to wait. Off by default.
+    pub fn set_rate_limit(&mut self, limit: usize, window: Duration) {
+        self.config.rate_limit = Some(Arc::new(RateLimiter::new(limit, window)));
+    }
+
+    /// Lets clients in a range of addresses connect, like `192.168.0.0/16`
+    /// or `::1`.
+    ///
+    /// Once any range is allowed, clients outside every allowed range are
+    /// refused with a 403.
+    pub fn allow_ip(&mut self, range: &str) -> Result<(), InvalidIpRange> {
+        self.config.ip_filter.allow(range)
+    }
+
+    /// Refuses clients in a range of addresses with a 403, even if they are
+    /// also allowed.
+    pub fn deny_ip(&mut self, range: &str) -> Result<(), InvalidIpRange> {
+        self.config.ip_filter.deny(range)
+    }
+
+    /// Sets how long `shutdown` waits for open connections to finish before
+    /// cutting them off.
+    ///
+    /// Handlers still running once it's up, such as ones stuck on something
+    /// other than their connection, are left behind so `start` can return.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn set_shutdown_grace_period(&mut self, grace: Duration) {
+        self.config.shutdown_grace = grace;
+    }
+
+    /// Limits how many connections are handled at once.
+    ///
+    /// Connections over the limit are answered with a 503 and closed without
+    /// the request being read. Off by default.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.config.max_connections = Some(max);
+    }
+
+    /// Runs `middleware` around every request.
+    ///
+    /// Middleware runs in the order it was added, so the first added sees the
+    /// request first and the response last.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.config.middleware.push(Arc::new(middleware));
+    }
+
+    /// Starts configuring a web server, as an alternative to calling `new` and
+    /// then each setter.
+    pub fn builder() -> WebServerBuilder {
+        WebServerBuilder::new()
+    }
+
+    /// Maps a path onto a file for the default host.
+    ///
+    /// See `Site::add_route`.
+    pub fn add_route(&self, path: &str, file: &str) {
+        self.sites.default.add_route(path, file);
+    }
+
+    /// Stops serving a file at a path for the default host.
+    ///
+    /// See `Site::remove_route`.
+    pub fn remove_route(&self, path: &str) {
+        self.sites.default.remove_route(path);
+    }
+
+    /// Serves a directory for the default host.
+    ///
+    /// See `Site::serve_dir`.
+    pub fn serve_dir(&self, url_prefix: &str, fs_root: &str) {
+        self.sites.default.serve_dir(url_prefix, fs_root);
+    }
+
+    /// Answers paths matching a regex for the default host.
+    ///
+    /// See `Site::route_regex`.
+    pub fn route_regex<F>(&self, pattern: regex::Regex, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.sites.default.route_regex(pattern, handler);
+    }
+
+    /// Redirects a path for the default host.
+    ///
+    /// See `Site::redirect`.
+    pub fn redirect(&self, from: &str, to: &str, permanent: bool) {
+        self.sites.default.redirect(from, to, permanent);
+    }
+
+    /// Answers a path by calling `handler` for the default host.
+    ///
+    /// See `Site::route`.
+    pub fn route<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.sites.default.route(path, handler);
+    }
+
+    /// Answers one method on a path for the default host.
+    ///
+    /// See `Site::route_method`.
+    pub fn route_method<F>(&self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.sites.default.route_method(method, path, handler);
+    }
+
+    /// Answers GET requests to a path for the default host.
+    ///
+    /// See `Site::get`.
+    pub fn get<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.sites.default.get(path, handler);
+    }
+
+    /// Answers POST requests to a path for the default host.
+    ///
+    /// See `Site::post`.
+    pub fn post<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.sites.default.post(path, handler);
+    }
+
+    /// Reports counts of requests, responses, and open connections at `path`
+    /// on the default host, in the Prometheus text format.
+    pub fn serve_metrics(&self, path: &str) {
+        let metrics = Arc::clone(&self.config.metrics);
+
+        self.route(path, move |_| {
+            let mut response = Response::new(metrics.render());
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+
+            response
+        });
+    }
+
+    /// Gets the routes served to requests for `hostname`, creating an empty
+    /// set if there aren't any yet.
+    ///
+    /// Requests are matched to a host by their `Host` header, ignoring case
+    /// and any port. Requests for hosts without their own routes use the
+    /// default host's, unless `set_strict_hosts` says otherwise.
+    pub fn host(&self, hostname: &str) -> Site {
+        self.sites
+            .hosts
+            .write()
+            .unwrap()
+            .entry(hostname.to_ascii_lowercase())
+            .or_insert_with(|| Site::new(Routes::new()))
+            .clone()
+    }
+
     /// Starts the web server.
-    pub fn start(&self, ip: &str) -> Result<(), Box<dyn Error>> {
-        // Create a listener on the address we want to respond to
-        let listener = TcpListener::bind(ip)?;
-        // Create a pool of threads to prevent the server from blocking
-        let pool = ThreadPool::new(self.thread_limit)?;
+    ///
+    /// Blocks until `shutdown` is called, after which every worker is joined
+    /// before returning.
+    pub fn start(&self, ip: &str) -> Result<(), ServerError> {
+        self.start_multi(&[ip])
+    }
 
-        // Start listening
-        for stream in listener.incoming() {
-            let stream = stream?;
+    /// Starts the web server listening on several addresses at once.
+    ///
+    /// Each address gets its own accept loop, all sharing the same workers
+    /// and routes. Nothing is served unless every address can be bound, and
+    /// the error lists each one that couldn't be. Blocks the same way `start`
+    /// does.
+    pub fn start_multi(&self, addrs: &[&str]) -> Result<(), ServerError> {
+        if addrs.is_empty() {
+            return Err(ServerError::Bind(Vec::new()));
+        }
 
-            let routes = Arc::clone(&self.routes);
+        let mut listeners = Vec::new();
+        let mut failures = Vec::new();
 
-            // Pass handling of the connection off to a seperate thread
-            pool.execute(|| {
-                handle_connection(routes, stream).unwrap();
-            })
+        // Create a listener on every address we want to respond to
+        for &addr in addrs {
+            let listener = TcpListener::bind(addr).and_then(|listener| {
+                // Don't block on accept so the stop flag gets a chance to be checked
+                listener.set_nonblocking(true)?;
+
+                Ok(listener)
+            });
+
+            match listener {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => failures.push((String::from(addr), err)),
+            }
         }
 
-        Ok(())
+        if !failures.is_empty() {
+            return Err(ServerError::Bind(failures));
+        }
+
+        self.serve(&listeners)
     }
-}
 
-/// Handles an individual connection.
-///
-/// Performed by threads.
-fn handle_connection(
-    routes: Arc<RwLock<Routes>>,
-    mut stream: TcpStream,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer)?;
-
-    let request = parse_request(&buffer);
-
-    // Pass on the request
-    let response = response(routes, request).unwrap();
-
-    // Parse the response back into a format we can send back
-    let response = format!(
-        "{:?} {}\r\n\r\n{}",
-        response.version(),
-        response.status(),
-        response.body()
-    );
-
-    // Send the response back
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    /// Starts the web server listening on a Unix domain socket at `path`.
+    ///
+    /// Handy behind a reverse proxy on the same machine. Clients have no IP
+    /// address, so IP filtering and rate limiting let them all through. The
+    /// socket file is removed once the server stops. Blocks the same way
+    /// `start` does.
+    #[cfg(unix)]
+    pub fn start_unix(&self, path: &str) -> Result<(), ServerError> {
+        let listener = UnixListener::bind(path).and_then(|listener| {
+            listener.set_nonblocking(true)?;
 
-    Ok(())
-}
+            Ok(listener)
+        });
+        let listener =
+            listener.map_err(|err| ServerError::Bind(vec![(String::from(path), err)]))?;
 
-fn parse_request(buffer: &[u8]) -> Request<&[u8]> {
-    lazy_static! {
-        static ref LINES: Regex = Regex::new(r"(.*?)\r?\n").unwrap();
+        let result = self.serve(&[listener]);
+        let _ = fs::remove_file(path);
+
+        result
     }
 
-    let mut lines = LINES.find_iter(buffer);
+    /// Serves connections from every listener until the server stops.
+    fn serve<L: Listener>(&self, listeners: &[L]) -> Result<(), ServerError> {
+        // Create a pool of threads to prevent the server from blocking
+        let pool = ThreadPool::builder()
+            .size(self.thread_limit)
+            .name_prefix("server-worker")
+            .build()?;
+        let pool = Arc::new(pool);
 
-    // Parse the first line
-    let first_line: &[u8] = &buffer[lines.next().unwrap().range()];
+        let config = Arc::new(self.config.clone());
+        config.metrics.watch_pool(&pool);
+        // Set when any accept loop fails, so the others stop too
+        let failed = AtomicBool::new(false);
 
-    lazy_static! {
-        static ref TOKENS: Regex = Regex::new(r"(.*?)\s").unwrap();
-    }
+        let result = thread::scope(|scope| {
+            let (pool, config, failed) = (&*pool, &config, &failed);
 
-    let mut tokens = TOKENS.find_iter(first_line);
+            let loops: Vec<_> = listeners
+                .iter()
+                .map(|listener| {
+                    scope.spawn(move || {
+                        let result = self.accept_loop(listener, pool, config, failed);
 
-    let method: &[u8] = &first_line[tokens.next().unwrap().range()];
-    let uri: &[u8] = &first_line[tokens.next().unwrap().range()];
-    let version: &[u8] = &first_line[tokens.next().unwrap().range()];
+                        if result.is_err() {
+                            failed.store(true, Ordering::SeqCst);
+                        }
 
-    println!("{}", String::from_utf8_lossy(version));
+                        result
+                    })
+                })
+                .collect();
 
-    let version = match version {
-        b"HTTP/0.9" => Version::HTTP_09,
-        b"HTTP/1.0" => Version::HTTP_10,
-        b"HTTP/1.1" => Version::HTTP_11,
-        b"HTTP/2.0" => Version::HTTP_2,
-        b"HTTP/3.0" => Version::HTTP_3,
-        _ => unreachable!(),
-    };
+            loops
+                .into_iter()
+                .try_for_each(|accept_loop| accept_loop.join().unwrap())
+        });
 
-    // Start building the request with the information we have so far
-    let mut request = Request::builder().method(method).uri(uri).version(version);
+        // Let requests already being handled finish
+        let deadline = Instant::now() + config.shutdown_grace;
+        let cut_off = self.connections.drain(config.shutdown_grace);
 
-    // Store the regex for headers statically to save processing time
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"(?P<key>.*?):(?P<value>.*)").unwrap();
+        if cut_off > 0 {
+            log::warn!("cut off {} connections still open at shutdown", cut_off);
+        }
+
+        // Handlers whose connections were just cut off need a moment to notice
+        let deadline = deadline.max(Instant::now() + CUT_OFF_LINGER);
+
+        // Rendering the metrics only borrows the pool for a moment
+        let mut pool = pool;
+        let pool = loop {
+            match Arc::try_unwrap(pool) {
+                Ok(pool) => break Some(pool),
+                Err(shared) if Instant::now() < deadline => {
+                    pool = shared;
+                    thread::yield_now();
+                }
+                Err(_) => break None,
+            }
+        };
+
+        let left = deadline.saturating_duration_since(Instant::now());
+        match pool.map(|pool| pool.shutdown_timeout(left)) {
+            Some(Ok(())) => {}
+            Some(Err(stuck)) => log::error!("gave up on stuck workers {:?} at shutdown", stuck),
+            None => log::error!("gave up on the pool at shutdown, a job is holding on to it"),
+        }
+
+        Ok(result?)
     }
 
-    // Parse the headers
-    loop {
-        match lines.next() {
-            None => break,
-            Some(line) => {
-                let range = line.range();
+    /// Hands connections from a listener to the pool until the server stops.
+    fn accept_loop<L: Listener>(
+        &self,
+        listener: &L,
+        pool: &ThreadPool,
+        config: &Arc<Config>,
+        failed: &AtomicBool,
+    ) -> io::Result<()> {
+        // Start listening
+        loop {
+            if self.stopped.load(Ordering::SeqCst) || failed.load(Ordering::SeqCst) {
+                break;
+            }
 
-                if range.start == range.end {
-                    break;
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                // The client gave up before it could be accepted
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            // One bad connection shouldn't take the listener down with it
+            if let Err(err) = stream.set_nonblocking(false) {
+                log::warn!("could not set up connection: {}", err);
+                continue;
+            }
+
+            // Shed load here rather than queue up work the pool can't get to
+            if config
+                .max_connections
+                .is_some_and(|max| self.connections.len() >= max)
+            {
+                refuse_busy(config, stream);
+                continue;
+            }
+
+            let sites = Arc::clone(&self.sites);
+            let cache = Arc::clone(&self.cache);
+            let config = Arc::clone(config);
+            let stopped = Arc::clone(&self.stopped);
+            let tracked = self.connections.track(&stream);
+
+            // Pass handling of the connection off to a seperate thread
+            let handed_off = pool.execute(|| {
+                if let Err(err) = handle_socket(sites, cache, config, stopped, stream) {
+                    log::warn!("connection failed: {}", err);
                 }
 
-                let header = RE.captures(&buffer[range]).unwrap();
+                drop(tracked);
+            });
 
-                request = request.header(&header["key"], &header["value"]);
+            if let Err(err) = handed_off {
+                log::error!("could not handle connection: {}", err);
+                break;
             }
         }
+
+        Ok(())
     }
 
-    // Build the body from the remaining lines
-    let body = match lines.next() {
-        None => &buffer[0..0],
-        Some(line) => &buffer[line.start()..],
-    };
+    /// Stops a running server.
+    ///
+    /// No new connections are accepted, and connections being handled are
+    /// closed once their current request is answered. `start` returns once
+    /// they have all finished, or the grace period runs out and the rest are
+    /// cut off.
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
 
-    // Turn the body back into bytes
-    request.body(body).unwrap()
+    /// Shuts the server down, as with `shutdown`, on `SIGTERM` or `SIGINT`.
+    ///
+    /// Signals are left alone unless this is called, so a server embedded in
+    /// another program doesn't take them over. Once registered the signals no
+    /// longer kill the process, so it's up to the caller to exit after `start`
+    /// returns.
+    #[cfg(feature = "signals")]
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, Arc::clone(&self.stopped))?;
+        }
+
+        Ok(())
+    }
 }
 
-fn response(
-    routes: Arc<RwLock<Routes>>,
-    request: Request<&[u8]>,
-) -> http::Result<Response<String>> {
-    let method = request.method();
+/// Handles a connection accepted from a listener.
+///
+/// Performed by threads.
+fn handle_socket<S: Socket>(
+    sites: Arc<Sites>,
+    cache: Arc<FileCache>,
+    config: Arc<Config>,
+    stopped: Arc<AtomicBool>,
+    mut stream: S,
+) -> Result<(), ServerError> {
+    stream.set_read_timeout(config.read_timeout)?;
 
-    match *method {
-        Method::GET | Method::POST => match routes.read().unwrap().get(request.uri().path()) {
-            Some(file) => {
-                let body = fs::read_to_string(file).unwrap();
+    let peer = stream.peer_addr();
 
-                Response::builder().status(200).body(body)
-            }
-            None => {
-                let body = fs::read_to_string("404.html").unwrap();
+    // Turn away filtered clients before reading anything they send
+    if !peer.is_none_or(|peer| config.ip_filter.permits(peer.ip())) {
+        let mut response = closing_response(403, "Forbidden")?;
+        add_standard_headers(&config, &mut response);
+        config.metrics.response_sent(response.status());
+        write_response(&mut stream, response, false)?;
 
-                Response::builder().status(404).body(body)
-            }
-        },
-        Method::HEAD | Method::OPTIONS => Response::builder()
-            .status(501)
-            .body(format!("Server does not support {} requests", method)),
-        _ => Response::builder()
-            .status(405)
-            .body(format!("Server does not allow {} requests", method)),
+        hang_up(&mut stream, &config);
+
+        return Ok(());
+    }
+
+    let ending = handle_connection(&sites, &cache, &config, &stopped, &mut stream, peer)?;
+
+    if let Ending::Refused = ending {
+        hang_up(&mut stream, &config);
+    }
+
+    Ok(())
+}
+
+/// How a connection came to an end.
+enum Ending {
+    /// Either side was done.
+    Finished,
+    /// A request was turned away part way through, and the client may still be
+    /// sending it.
+    Refused,
+}
+
+/// Answers requests read off a stream until either side is done with it.
+///
+/// Works with any stream of bytes, so sockets are set up beforehand and it's
+/// left to the caller to close them.
+fn handle_connection<S: Read + Write>(
+    sites: &Sites,
+    cache: &FileCache,
+    config: &Config,
+    stopped: &AtomicBool,
+    stream: S,
+    peer: Option<SocketAddr>,
+) -> Result<Ending, ServerError> {
+    let mut stream = BufReader::with_capacity(config.buffer_size, stream);
+    // Made after the stream so it's dropped before the connection closes
+    let _active = config.metrics.connection_opened();
+    let mut served = 0;
+
+    // Keep answering requests on the same connection until one side is done
+    loop {
+        let buffer = match read_request(&mut stream, config) {
+            Ok(buffer) => buffer,
+            // The request can't be read, so tell the client why and hang up
+            Err(err) => {
+                let response = match err {
+                    ReadError::TooLarge => closing_response(413, "Request too large"),
+                    ReadError::HeadersTooLarge => {
+                        closing_response(431, "Request header fields too large")
+                    }
+                    ReadError::AmbiguousLength => {
+                        closing_response(400, "Bad request: unclear body length")
+                    }
+                    ReadError::TransferEncoding => {
+                        closing_response(501, "Transfer encodings are not supported")
+                    }
+                    ReadError::Io(err) if is_timeout(&err) => {
+                        closing_response(408, "Request timed out")
+                    }
+                    ReadError::Io(err) => return Err(err.into()),
+                };
+
+                let mut response = response?;
+                add_standard_headers(config, &mut response);
+                config.metrics.response_sent(response.status());
+
+                write_response(stream.get_mut(), response, false)?;
+
+                return Ok(Ending::Refused);
+            }
+        };
+
+        // The client hung up
+        if buffer.is_empty() {
+            break;
+        }
+
+        served += 1;
+        config.metrics.request_received();
+
+        let request = parse_request(&buffer);
+
+        // Only the head of a response to a HEAD request is sent
+        let head_only = matches!(&request, Ok(request) if request.method() == Method::HEAD);
+        let gzip = matches!(&request, Ok(request) if accepts_gzip(request));
+        // Older clients have to be told when the connection is staying open
+        let before_1_1 = matches!(&request, Ok(request) if request.version() < Version::HTTP_11);
+
+        // Pass on the request, or turn it away if it doesn't make sense
+        let (mut response, keep_alive) = match request {
+            Ok(request) => {
+                let keep_alive = wants_keep_alive(&request);
+
+                // Let handlers see who's asking, when that's known
+                let mut request = request.map(<[u8]>::to_vec);
+                if let Some(peer) = peer {
+                    request.extensions_mut().insert(peer);
+                }
+
+                // Only time the handling, not reading off the socket
+                let start = Instant::now();
+                let response = match rate_limited(config, peer) {
+                    Some(response) => response?,
+                    None => response(sites, cache, config, request),
+                };
+
+                if config.access_log {
+                    let request_line = buffer.split(|&b| b == b'\n').next().unwrap_or(&[]);
+                    let request_line = String::from_utf8_lossy(trim_line_ending(request_line));
+
+                    log::info!(
+                        target: "access",
+                        "{}",
+                        access_log_line(
+                            peer,
+                            &request_line,
+                            &response,
+                            SystemTime::now(),
+                            start.elapsed()
+                        )
+                    );
+                }
+
+                (response, keep_alive)
+            }
+            // We can't trust where a malformed request ends, so stop here
+            Err(err) => (
+                Response::builder()
+                    .status(400)
+                    .body(format!("Bad request: {}", err).into_bytes())?,
+                false,
+            ),
+        };
+
+        response = compress(response, gzip)?;
+
+        let keep_alive = keep_alive
+            && served < config.max_requests_per_connection
+            && !stopped.load(Ordering::SeqCst);
+
+        if !keep_alive {
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+        } else if before_1_1 {
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        }
+
+        add_standard_headers(config, &mut response);
+        config.metrics.response_sent(response.status());
+
+        // Send the response back
+        write_response(stream.get_mut(), response, head_only)?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(Ending::Finished)
+}
+
+/// Turns away a connection while the server is too busy to handle it.
+fn refuse_busy<S: Socket>(config: &Config, mut stream: S) {
+    let response = Response::builder()
+        .status(503)
+        .header(CONNECTION, "close")
+        .header(RETRY_AFTER, 1)
+        .body(b"Service unavailable".to_vec());
+
+    if let Ok(mut response) = response {
+        add_standard_headers(config, &mut response);
+        config.metrics.response_sent(response.status());
+
+        // This runs on the accept loop, so it can't wait around on the client
+        let _ = stream.set_write_timeout(Some(REFUSAL_TIMEOUT));
+        let _ = write_response(&mut stream, response, false);
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Ends a connection while the client may still be sending.
+///
+/// Closing with a request unread would reset the connection, which can lose
+/// the response, so the client gets a moment to finish sending first. A
+/// client trickling bytes in is cut off once `LINGER_LIMIT` is up.
+fn hang_up<S: Socket>(stream: &mut S, config: &Config) {
+    // The connection is going away regardless, so errors don't matter here
+    let _ = stream.shutdown(Shutdown::Write);
+
+    let deadline = Instant::now() + LINGER_LIMIT;
+    let mut unread = config.max_request_bytes;
+    let mut buffer = [0; 4096];
+
+    while unread > 0 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+
+        let _ = stream.set_read_timeout(Some(left.min(LINGER_TIMEOUT)));
+        match stream.read(&mut buffer[..unread.min(4096)]) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => unread -= read,
+        }
+    }
+}
+
+/// Turns away a client that has gone over the rate limit.
+fn rate_limited(
+    config: &Config,
+    peer: Option<SocketAddr>,
+) -> Option<http::Result<Response<Vec<u8>>>> {
+    let limiter = config.rate_limit.as_ref()?;
+    let retry_after = limiter.check(peer?.ip(), Instant::now()).err()?;
+
+    // Round up so clients don't come back a moment too soon
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    Some(
+        Response::builder()
+            .status(429)
+            .header(RETRY_AFTER, seconds)
+            .body(b"Too many requests".to_vec()),
+    )
+}
+
+/// Formats a line for the access log.
+///
+/// This is the common log format with how long handling took tacked on, like
+/// `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET / HTTP/1.1" 200 206 1.2ms`.
+fn access_log_line(
+    peer: Option<SocketAddr>,
+    request_line: &str,
+    response: &Response<Vec<u8>>,
+    time: SystemTime,
+    elapsed: Duration,
+) -> String {
+    let peer = peer.map_or_else(|| String::from("-"), |peer| peer.ip().to_string());
+
+    format!(
+        "{} - - [{}] \"{}\" {} {} {:?}",
+        peer,
+        format_log_date(time),
+        request_line,
+        response.status().as_u16(),
+        response.body().len(),
+        elapsed
+    )
+}
+
+/// Adds the `Date` and `Server` headers every response carries.
+fn add_standard_headers<B>(config: &Config, response: &mut Response<B>) {
+    let headers = response.headers_mut();
+
+    if let Ok(date) = HeaderValue::from_str(&format_http_date(SystemTime::now())) {
+        headers.insert(DATE, date);
+    }
+
+    // Handlers are free to name themselves
+    if !headers.contains_key(SERVER) {
+        if let Ok(name) = HeaderValue::from_str(&config.server_name) {
+            headers.insert(SERVER, name);
+        }
+    }
+}
+
+/// Whether the client will take a gzipped response.
+fn accepts_gzip<B>(request: &Request<B>) -> bool {
+    request
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+
+            // A quality of zero means the coding is explicitly refused
+            let refused = params.any(|param| {
+                let param = param.trim();
+
+                param.starts_with("q=") && param[2..].parse::<f32>() == Ok(0.0)
+            });
+
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Gzips the body of a response if it's text big enough to benefit, and the
+/// client will take it.
+///
+/// Either way a response that could have been gzipped tells caches it depends
+/// on `Accept-Encoding`, and a gzipped one gets its own ETag.
+fn compress(mut response: Response<Vec<u8>>, gzip: bool) -> io::Result<Response<Vec<u8>>> {
+    let compressible = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_compressible);
+
+    if !compressible
+        || response.body().len() < GZIP_MIN_BYTES
+        || response.headers().contains_key(CONTENT_ENCODING)
+        // A range is a slice of the unencoded body
+        || response.headers().contains_key(CONTENT_RANGE)
+    {
+        return Ok(response);
+    }
+
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    if !gzip {
+        return Ok(response);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(response.body())?;
+    *response.body_mut() = encoder.finish()?;
+
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+    let etag = headers.get(ETAG).and_then(|etag| etag.to_str().ok());
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&gzip_etag(etag)).ok()) {
+        headers.insert(ETAG, etag);
+    }
+
+    Ok(response)
+}
+
+/// The ETag for the gzipped version of what `etag` tags.
+///
+/// The bytes sent differ, so a strong ETag can't be shared between them.
+fn gzip_etag(etag: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque) => format!("{}-gzip\"", opaque),
+        None => format!("{}-gzip", etag),
+    }
+}
+
+/// Whether a MIME type is text that compresses well.
+fn is_compressible(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.starts_with("application/javascript")
+        || mime.starts_with("application/json")
+        || mime.starts_with("application/xml")
+        || mime.starts_with("image/svg+xml")
+}
+
+/// The token an HTTP version is written as on the wire, like `HTTP/1.1`.
+pub fn version_str(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        // `Version` may grow more variants, and 1.1 is what everyone speaks
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Formats a status line like `HTTP/1.1 200 OK`.
+///
+/// Statuses without a canonical reason get an empty reason phrase.
+fn status_line(version: Version, status: StatusCode) -> String {
+    format!(
+        "{} {} {}\r\n",
+        version_str(version),
+        status.as_str(),
+        status.canonical_reason().unwrap_or("")
+    )
+}
+
+/// Builds a response that ends the connection.
+fn closing_response(status: u16, body: &str) -> http::Result<Response<Vec<u8>>> {
+    Response::builder()
+        .status(status)
+        .header(CONNECTION, "close")
+        .body(Vec::from(body))
+}
+
+/// Whether a read failed because the read timeout ran out.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Whether the client wants the connection kept open after this request.
+///
+/// HTTP/1.1 connections stay open unless the client says `Connection: close`,
+/// while older ones close unless it says `Connection: keep-alive`.
+fn wants_keep_alive<B>(request: &Request<B>) -> bool {
+    let has_token = |wanted: &str| {
+        request
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case(wanted))
+    };
+
+    match request.version() {
+        Version::HTTP_09 | Version::HTTP_10 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
+}
+
+/// A file to send as the body of a response, in place of the actual body.
+///
+/// Only as much of the file as the `Take` allows is sent.
+struct StreamFile(io::Take<fs::File>);
+
+/// Serializes a response onto the wire.
+///
+/// Every header on the response is written, along with a `Content-Length`
+/// matching the body. Responses carrying a `StreamFile` are sent chunked
+/// instead. With `head_only` the body itself is left off, as is done when
+/// answering a HEAD request.
+fn write_response<W: Write>(
+    stream: &mut W,
+    mut response: Response<Vec<u8>>,
+    head_only: bool,
+) -> io::Result<()> {
+    let stream_file = response.extensions_mut().remove::<StreamFile>();
+
+    // These statuses never have a body to give the length of
+    let status = response.status();
+    let bodiless = status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+
+    if stream_file.is_some() {
+        response
+            .headers_mut()
+            .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    } else if !bodiless {
+        let length = HeaderValue::from(response.body().len());
+        response.headers_mut().insert(CONTENT_LENGTH, length);
+    }
+
+    let mut head = status_line(response.version(), response.status()).into_bytes();
+
+    for (key, value) in response.headers() {
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+
+    head.extend_from_slice(b"\r\n");
+
+    stream.write_all(&head)?;
+
+    if head_only {
+        return stream.flush();
+    }
+
+    match stream_file {
+        Some(StreamFile(file)) => write_chunked(stream, file)?,
+        None => stream.write_all(response.body())?,
+    }
+
+    stream.flush()
+}
+
+/// Copies a file onto the wire using chunked transfer encoding.
+fn write_chunked<W: Write, R: Read>(stream: &mut W, mut file: R) -> io::Result<()> {
+    let mut chunk = vec![0; STREAM_CHUNK_BYTES];
+
+    loop {
+        let read = file.read(&mut chunk)?;
+
+        // An empty chunk marks the end of the body
+        if read == 0 {
+            return stream.write_all(b"0\r\n\r\n");
+        }
+
+        write!(stream, "{:x}\r\n", read)?;
+        stream.write_all(&chunk[..read])?;
+        stream.write_all(b"\r\n")?;
+    }
+}
+
+/// Reads a single request off of the stream.
+///
+/// The request line and headers are read line by line until the blank line,
+/// then exactly `Content-Length` bytes are read for the body. Reading fails
+/// if the request would grow past the configured size, has too many or too
+/// long headers, or doesn't make clear where its body ends.
+///
+/// Clients sending `Expect: 100-continue` are told to go ahead with a
+/// `100 Continue` before the body is read, unless it's already too large.
+fn read_request<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    config: &Config,
+) -> Result<Vec<u8>, ReadError> {
+    let max_bytes = config.max_request_bytes;
+    let mut buffer = Vec::new();
+    let mut content_length = None;
+    let mut transfer_encoding = false;
+    let mut headers = 0;
+    let mut expects_continue = false;
+
+    // Read the request line and headers
+    for index in 0.. {
+        let start = buffer.len();
+        let is_header = index > 0;
+
+        let mut limit = max_bytes - start;
+        if is_header {
+            // Leave room for the line ending
+            limit = limit.min(config.max_header_bytes + 2);
+        }
+
+        if reader
+            .by_ref()
+            .take(limit as u64)
+            .read_until(b'\n', &mut buffer)?
+            == 0
+        {
+            // The connection closed before the headers finished
+            break;
+        }
+
+        if !buffer.ends_with(b"\n") {
+            if buffer.len() >= max_bytes {
+                return Err(ReadError::TooLarge);
+            }
+
+            if buffer.len() - start >= limit {
+                return Err(ReadError::HeadersTooLarge);
+            }
+
+            break;
+        }
+
+        let line = trim_line_ending(&buffer[start..]);
+
+        // A blank line marks the end of the headers
+        if line.is_empty() {
+            break;
+        }
+
+        if is_header {
+            headers += 1;
+
+            if headers > config.max_headers || line.len() > config.max_header_bytes {
+                return Err(ReadError::HeadersTooLarge);
+            }
+        }
+
+        // Guessing at where the body ends would let the rest of it be taken
+        // for the next request
+        if let Some(value) = header_value(line, b"content-length") {
+            let length = parse_content_length(value).ok_or(ReadError::AmbiguousLength)?;
+
+            if content_length.is_some_and(|known| known != length) {
+                return Err(ReadError::AmbiguousLength);
+            }
+
+            content_length = Some(length);
+        }
+
+        transfer_encoding |= header_value(line, b"transfer-encoding").is_some();
+        expects_continue |= header_value(line, b"expect")
+            .is_some_and(|value| value.eq_ignore_ascii_case(b"100-continue"));
+    }
+
+    if transfer_encoding {
+        return Err(match content_length {
+            Some(_) => ReadError::AmbiguousLength,
+            None => ReadError::TransferEncoding,
+        });
+    }
+
+    let content_length = content_length.unwrap_or(0);
+
+    if content_length > max_bytes - buffer.len() {
+        return Err(ReadError::TooLarge);
+    }
+
+    // HTTP/1.0 clients don't know about interim responses
+    let is_http_11 = buffer
+        .split(|&b| b == b'\n')
+        .next()
+        .is_some_and(|line| trim_line_ending(line).ends_with(b"HTTP/1.1"));
+
+    if expects_continue && is_http_11 && content_length > 0 {
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        reader.get_mut().flush()?;
+    }
+
+    // Read exactly as much body as we were told to expect
+    let start = buffer.len();
+    buffer.resize(start + content_length, 0);
+    reader.read_exact(&mut buffer[start..])?;
+
+    Ok(buffer)
+}
+
+/// Why a request couldn't be read off the stream.
+#[derive(Debug)]
+enum ReadError {
+    /// The request was bigger than allowed.
+    TooLarge,
+    /// There were too many headers, or one was too long.
+    HeadersTooLarge,
+    /// The `Content-Length` was invalid, or disagreed with another.
+    AmbiguousLength,
+    /// The body was sent with a transfer coding, which isn't supported.
+    TransferEncoding,
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> ReadError {
+        ReadError::Io(err)
+    }
+}
+
+/// Strips a trailing `\n` or `\r\n` from a line.
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// The trimmed value of a header line, if it's the header called `name`.
+fn header_value<'a>(line: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let colon = line.iter().position(|&b| b == b':')?;
+    let (key, value) = line.split_at(colon);
+
+    if !key.eq_ignore_ascii_case(name) {
+        return None;
+    }
+
+    Some(value[1..].trim_ascii())
+}
+
+/// Parses a `Content-Length`, which must be nothing but digits.
+fn parse_content_length(value: &[u8]) -> Option<usize> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn parse_request(buffer: &[u8]) -> Result<Request<&[u8]>, ParseError> {
+    lazy_static! {
+        static ref LINES: Regex = Regex::new(r"(.*?)\r?\n").unwrap();
+    }
+
+    let mut lines = LINES.captures_iter(buffer);
+
+    // Parse the first line
+    let first_line: &[u8] = match lines.next() {
+        Some(line) => &buffer[line.get(1).unwrap().range()],
+        None => return Err(ParseError::EmptyRequestLine),
+    };
+
+    if first_line.is_empty() {
+        return Err(ParseError::EmptyRequestLine);
+    }
+
+    lazy_static! {
+        static ref TOKENS: Regex = Regex::new(r"\S+").unwrap();
+    }
+
+    let mut tokens = TOKENS.find_iter(first_line);
+
+    let mut next_token = || {
+        tokens
+            .next()
+            .map(|token| &first_line[token.range()])
+            .ok_or(ParseError::MissingToken)
+    };
+
+    let method: &[u8] = next_token()?;
+    let uri: &[u8] = next_token()?;
+    let version: &[u8] = next_token()?;
+
+    let version = match version {
+        b"HTTP/0.9" => Version::HTTP_09,
+        b"HTTP/1.0" => Version::HTTP_10,
+        b"HTTP/1.1" => Version::HTTP_11,
+        b"HTTP/2.0" => Version::HTTP_2,
+        b"HTTP/3.0" => Version::HTTP_3,
+        _ => return Err(ParseError::UnknownVersion),
+    };
+
+    // Start building the request with the information we have so far
+    let mut request = Request::builder().method(method).uri(uri).version(version);
+
+    // Store the regex for headers statically to save processing time
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^(?P<key>[^:]+):\s*(?P<value>.*?)\s*$").unwrap();
+    }
+
+    // The body starts after the blank line, or is empty if there isn't one
+    let mut body_start = buffer.len();
+
+    // Parse the headers
+    for line in lines {
+        let range = line.get(1).unwrap().range();
+
+        if range.start == range.end {
+            body_start = line.get(0).unwrap().end();
+            break;
+        }
+
+        let header = RE
+            .captures(&buffer[range])
+            .ok_or(ParseError::MalformedHeader)?;
+
+        request = request.header(&header["key"], &header["value"]);
+    }
+
+    // Turn the body back into bytes
+    Ok(request.body(&buffer[body_start..])?)
+}
+
+/// Answers a request, running it through the middleware and then the router.
+fn response(
+    sites: &Sites,
+    cache: &FileCache,
+    config: &Config,
+    request: Request<Vec<u8>>,
+) -> Response<Vec<u8>> {
+    let endpoint = |request: Request<Vec<u8>>| {
+        let response = match sites.find(&request, config.strict_hosts) {
+            Some(site) => route(&site.routes, cache, config, request),
+            None => not_found(config),
+        };
+
+        response.unwrap_or_else(|err| {
+            log::error!("could not build response: {}", err);
+
+            internal_error(config)
+        })
+    };
+
+    // A panicking handler shouldn't take the worker down with it
+    let response = panic::catch_unwind(AssertUnwindSafe(|| {
+        Next::new(&config.middleware, &endpoint).run(request)
+    }));
+
+    response.unwrap_or_else(|payload| {
+        let message = thread_pool::panic_message(&*payload);
+        log::error!("request handler panicked: {}", message);
+
+        internal_error(config)
+    })
+}
+
+/// Responds to a request that couldn't be answered because of a bug.
+fn internal_error(config: &Config) -> Response<Vec<u8>> {
+    error_page(
+        config,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Internal server error",
+    )
+}
+
+/// Finds what a request is routed to and answers it.
+fn route(
+    routes: &RwLock<Router>,
+    cache: &FileCache,
+    config: &Config,
+    mut request: Request<Vec<u8>>,
+) -> http::Result<Response<Vec<u8>>> {
+    // Routes are registered unencoded, so match against the decoded path
+    let path = match request::percent_decode(request.uri().path()) {
+        Some(path) => path,
+        None => {
+            return Response::builder()
+                .status(400)
+                .body(b"Bad request: malformed percent-encoding in path".to_vec())
+        }
+    };
+    let mut path = path;
+
+    if config.path_normalization != PathNormalization::Off {
+        let raw = request.uri().path();
+        let normalized = normalize_path(raw);
+
+        // Decoding can turn up slashes the client didn't send as such, which
+        // a redirect would never get rid of
+        if config.path_normalization == PathNormalization::Redirect && normalized != raw {
+            return redirect_to_path(&request, &normalized);
+        }
+
+        path = normalize_path(&path);
+    }
+
+    if config.trailing_slash != TrailingSlash::Strict {
+        if let Some(other) = other_slash(routes, &path) {
+            if config.trailing_slash == TrailingSlash::Redirect {
+                // Keep the path as it was sent, escapes and all
+                return redirect_to_path(&request, &toggle_slash(request.uri().path()));
+            }
+
+            path = other;
+        }
+    }
+
+    let path = path.as_str();
+
+    // Handlers for a specific method come before all others
+    let handler = routes
+        .read()
+        .unwrap()
+        .method_handler(path, request.method());
+
+    match handler {
+        Some(Ok(handler)) => return Ok(handler(&request).map(String::into_bytes)),
+        Some(Err(allowed)) => {
+            return Response::builder().status(405).header(ALLOW, allowed).body(
+                format!("{} does not allow {} requests", path, request.method()).into_bytes(),
+            )
+        }
+        None => {}
+    }
+
+    // Dynamic handlers get first pick of every request
+    let handler = routes.read().unwrap().handler(path);
+
+    if let Some((handler, params)) = handler {
+        if let Some(params) = params {
+            request.extensions_mut().insert(params);
+        }
+
+        return Ok(handler(&request).map(String::into_bytes));
+    }
+
+    if let Some(redirect) = routes.read().unwrap().redirects.get(path) {
+        let status = if redirect.permanent { 301 } else { 302 };
+
+        return Response::builder()
+            .status(status)
+            .header(LOCATION, redirect.to.as_str())
+            .body(format!("Moved to {}", redirect.to).into_bytes());
+    }
+
+    let method = request.method();
+
+    match *method {
+        Method::GET | Method::HEAD | Method::POST => {
+            let routes = routes.read().unwrap();
+
+            if let Some(file) = routes.files.get(path) {
+                return serve_file(cache, config, &request, Path::new(file));
+            }
+
+            match routes.dir_file(path, &config.index_file) {
+                Some(DirFile::Found(file)) => serve_file(cache, config, &request, &file),
+                Some(DirFile::Forbidden) => {
+                    Ok(error_page(config, StatusCode::FORBIDDEN, "Forbidden"))
+                }
+                None => not_found(config),
+            }
+        }
+        Method::OPTIONS => Response::builder()
+            .status(501)
+            .body(format!("Server does not support {} requests", method).into_bytes()),
+        _ => Response::builder()
+            .status(405)
+            .header(ALLOW, "GET, HEAD, POST")
+            .body(format!("Server does not allow {} requests", method).into_bytes()),
+    }
+}
+
+/// Finds the route a path would match with its trailing slash added or taken
+/// away, if it doesn't match one as it is.
+fn other_slash(routes: &RwLock<Router>, path: &str) -> Option<String> {
+    let routes = routes.read().unwrap();
+
+    if path == "/" || routes.is_routed(path) {
+        return None;
+    }
+
+    Some(toggle_slash(path)).filter(|other| routes.is_routed(other))
+}
+
+/// Collapses repeated slashes in a path and drops `.` segments.
+///
+/// `..` segments are left for whatever the path is routed to to deal with.
+fn normalize_path(path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+
+    // A path naming a directory still does once normalized
+    if segments.is_empty() || path.ends_with('/') || path.ends_with("/.") {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+/// Takes the trailing slash off a path if it has one, or adds one if not.
+fn toggle_slash(path: &str) -> String {
+    match path.strip_suffix('/') {
+        Some(trimmed) => String::from(trimmed),
+        None => format!("{}/", path),
+    }
+}
+
+/// Permanently redirects a request to another path, keeping its query.
+fn redirect_to_path<B>(request: &Request<B>, path: &str) -> http::Result<Response<Vec<u8>>> {
+    let location = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => String::from(path),
+    };
+
+    Response::builder()
+        .status(301)
+        .header(LOCATION, location.as_str())
+        .body(format!("Moved to {}", location).into_bytes())
+}
+
+/// Responds with the contents of a file, or a 404 if it can't be read.
+///
+/// Clients that already hold the current version get a 304 instead, going by
+/// `If-None-Match` if it was sent and `If-Modified-Since` otherwise. Large
+/// files are streamed rather than read into memory. A `Range` header gets
+/// just the bytes it asks for.
+fn serve_file<B>(
+    cache: &FileCache,
+    config: &Config,
+    request: &Request<B>,
+    path: &Path,
+) -> http::Result<Response<Vec<u8>>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return not_found(config),
+    };
+
+    // HTTP/1.0 clients can't take a chunked response, so they get it buffered
+    if metadata.len() >= STREAM_MIN_BYTES && request.version() != Version::HTTP_10 {
+        return stream_file(config, request, path, &metadata);
+    }
+
+    let file = match cache.read(path) {
+        Ok(file) => file,
+        Err(_) => return not_found(config),
+    };
+
+    let response = Response::builder().header(LAST_MODIFIED, format_http_date(file.modified));
+
+    // A client holding the gzipped version is sent back its ETag
+    let gzipped_etag = gzip_etag(&file.etag);
+    if etag_matches(request, &gzipped_etag) {
+        return response
+            .status(304)
+            .header(ETAG, gzipped_etag)
+            .header(VARY, "accept-encoding")
+            .body(Vec::new());
+    }
+
+    let response = response.header(ETAG, file.etag.as_str());
+
+    let unchanged = if request.headers().contains_key(IF_NONE_MATCH) {
+        etag_matches(request, &file.etag)
+    } else {
+        not_modified_since(request, file.modified)
+    };
+
+    if unchanged {
+        return response.status(304).body(Vec::new());
+    }
+
+    let (response, range) = ranged(request, response, file.contents.len() as u64);
+
+    match range {
+        Some(range) => response
+            .header(CONTENT_TYPE, mime_for(&path.to_string_lossy()))
+            .body(file.contents[range.start as usize..range.end as usize].to_vec()),
+        None => response.body(Vec::new()),
+    }
+}
+
+/// Responds with a file that will be streamed to the client in chunks.
+///
+/// Hashing the whole file for an ETag would defeat the point, so only
+/// `If-Modified-Since` is honored.
+fn stream_file<B>(
+    config: &Config,
+    request: &Request<B>,
+    path: &Path,
+    metadata: &fs::Metadata,
+) -> http::Result<Response<Vec<u8>>> {
+    let mut response = Response::builder();
+
+    if let Ok(modified) = metadata.modified() {
+        response = response.header(LAST_MODIFIED, format_http_date(modified));
+
+        if not_modified_since(request, modified) {
+            return response.status(304).body(Vec::new());
+        }
+    }
+
+    let (response, range) = ranged(request, response, metadata.len());
+
+    let range = match range {
+        Some(range) => range,
+        None => return response.body(Vec::new()),
+    };
+
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return not_found(config),
+    };
+
+    if file.seek(io::SeekFrom::Start(range.start)).is_err() {
+        return not_found(config);
+    }
+
+    response
+        .header(CONTENT_TYPE, mime_for(&path.to_string_lossy()))
+        .extension(StreamFile(file.take(range.end - range.start)))
+        .body(Vec::new())
+}
+
+/// Sets up a response for the part of a `len` byte body that the request's
+/// `Range` header asks for.
+///
+/// Gives back the bytes to send, which is all of them without a usable
+/// `Range`, or `None` when the range can't be satisfied and the response is
+/// already complete.
+fn ranged<B>(
+    request: &Request<B>,
+    response: http::response::Builder,
+    len: u64,
+) -> (http::response::Builder, Option<ops::Range<u64>>) {
+    let response = response.header(ACCEPT_RANGES, "bytes");
+
+    // Ranges only mean something for a GET
+    let range = match request.headers().get(RANGE) {
+        Some(value) if request.method() == Method::GET => value
+            .to_str()
+            .map_or(ByteRange::Whole, |value| parse_range(value, len)),
+        _ => ByteRange::Whole,
+    };
+
+    match range {
+        ByteRange::Whole => (response.status(200), Some(0..len)),
+        ByteRange::Partial { start, end } => {
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+
+            (
+                response.status(206).header(CONTENT_RANGE, content_range),
+                Some(start..end + 1),
+            )
+        }
+        ByteRange::Unsatisfiable => (
+            response
+                .status(416)
+                .header(CONTENT_RANGE, format!("bytes */{}", len)),
+            None,
+        ),
+    }
+}
+
+/// Whether `modified` is no later than the request's `If-Modified-Since`.
+///
+/// Dates that can't be parsed are ignored.
+fn not_modified_since<B>(request: &Request<B>, modified: SystemTime) -> bool {
+    let since = request
+        .headers()
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date);
+
+    // HTTP dates only go down to the second
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| UNIX_EPOCH + Duration::from_secs(elapsed.as_secs()))
+        .unwrap_or(modified);
+
+    since.is_some_and(|since| modified <= since)
+}
+
+/// Whether an `If-None-Match` header on the request covers `etag`.
+fn etag_matches<B>(request: &Request<B>, etag: &str) -> bool {
+    request
+        .headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        // Weak comparison is fine for deciding on a 304
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Responds with the not found page.
+fn not_found(config: &Config) -> http::Result<Response<Vec<u8>>> {
+    Ok(error_page(
+        config,
+        StatusCode::NOT_FOUND,
+        DEFAULT_NOT_FOUND_BODY,
+    ))
+}
+
+/// Responds with the page configured for an error status, or `fallback` if
+/// there isn't one.
+fn error_page(config: &Config, status: StatusCode, fallback: &str) -> Response<Vec<u8>> {
+    let page = config.error_pages.get(&status).and_then(|page| {
+        fs::read(page)
+            .map_err(|err| log::warn!("could not read {}: {}", page, err))
+            .ok()
+            .map(|body| (body, mime_for(page)))
+    });
+
+    let (body, mime) = page.unwrap_or_else(|| (Vec::from(fallback), "text/plain; charset=utf-8"));
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+
+    response
+}
+
+/// Guesses the MIME type of a file from its extension.
+///
+/// Unknown extensions are treated as arbitrary binary data.
+pub fn mime_for(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("webp") => "image/webp",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Why a request couldn't be parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request line was missing or blank.
+    EmptyRequestLine,
+    /// The request line didn't have a method, URI, and version.
+    MissingToken,
+    /// The request asked for an HTTP version we don't know about.
+    UnknownVersion,
+    /// A header line wasn't of the form `key: value`.
+    MalformedHeader,
+    /// The pieces of the request didn't fit together.
+    Http(http::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::EmptyRequestLine => write!(f, "request line is empty"),
+            ParseError::MissingToken => {
+                write!(f, "request line is missing a method, URI, or version")
+            }
+            ParseError::UnknownVersion => write!(f, "unknown HTTP version"),
+            ParseError::MalformedHeader => write!(f, "malformed header"),
+            ParseError::Http(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Why a server stopped, or couldn't start.
+#[derive(Debug)]
+pub enum ServerError {
+    /// Some addresses couldn't be listened on. None were given if empty.
+    Bind(Vec<(String, io::Error)>),
+    /// Talking to a client or accepting connections failed.
+    Io(io::Error),
+    /// There were no threads to handle connections with.
+    PoolCreation(PoolCreationError),
+    /// A response couldn't be built.
+    Http(http::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind(failures) if failures.is_empty() => {
+                write!(f, "no addresses to listen on")
+            }
+            ServerError::Bind(failures) => {
+                write!(f, "could not bind")?;
+
+                for (i, (addr, err)) in failures.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}: {}", separator, addr, err)?;
+                }
+
+                Ok(())
+            }
+            ServerError::Io(err) => write!(f, "{}", err),
+            ServerError::PoolCreation(err) => write!(f, "{}", err),
+            ServerError::Http(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Bind(_) => None,
+            ServerError::Io(err) => Some(err),
+            ServerError::PoolCreation(err) => Some(err),
+            ServerError::Http(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+impl From<PoolCreationError> for ServerError {
+    fn from(err: PoolCreationError) -> ServerError {
+        ServerError::PoolCreation(err)
+    }
+}
+
+impl From<http::Error> for ServerError {
+    fn from(err: http::Error) -> ServerError {
+        ServerError::Http(err)
+    }
+}
+
+impl From<http::Error> for ParseError {
+    fn from(err: http::Error) -> ParseError {
+        ParseError::Http(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Cursor,
+        net::TcpStream,
+        sync::{mpsc, Mutex},
+    };
+
+    /// Finds a local address nothing is listening on.
+    fn free_addr() -> String {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Starts a server on a free local port in the background.
+    fn spawn(server: WebServer) -> (Arc<WebServer>, String) {
+        let addr = free_addr();
+
+        let server = Arc::new(server);
+        let running = Arc::clone(&server);
+        let bind = addr.clone();
+        thread::spawn(move || running.start(&bind).unwrap());
+
+        (server, addr)
+    }
+
+    /// Connects to a server, waiting for it to come up if need be.
+    fn connect(addr: &str) -> TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                return stream;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("server at {} never came up", addr);
+    }
+
+    /// Sends a raw request and reads back the whole response.
+    fn send(addr: &str, request: &[u8]) -> Vec<u8> {
+        let mut stream = connect(addr);
+        stream.write_all(request).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        response
+    }
+
+    /// Sends a GET for `path` and reads back the whole response.
+    fn get(addr: &str, path: &str) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+
+        String::from_utf8(send(addr, request.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn reads_body_past_512_bytes() {
+        let body = "a".repeat(2048);
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+
+        let mut reader = BufReader::new(Cursor::new(raw.into_bytes()));
+        let buffer = read_request(&mut reader, &Config::default()).unwrap();
+        let request = parse_request(&buffer).unwrap();
+
+        assert_eq!(*request.body(), body.as_bytes());
+    }
+
+    #[test]
+    fn small_buffers_read_large_requests() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_buffer_size(16);
+        let (server, addr) = spawn(server);
+
+        server.route("/echo", |request| {
+            Response::new(String::from_utf8_lossy(request.body()).into_owned())
+        });
+
+        let sent = "abc".repeat(1000);
+        let raw = format!(
+            "POST /echo HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            sent.len(),
+            sent
+        );
+
+        let response = send(&addr, raw.as_bytes());
+
+        assert_eq!(body(&response), sent.as_bytes());
+    }
+
+    #[test]
+    fn malformed_request_lines_are_errors() {
+        assert!(matches!(
+            parse_request(b"\r\n"),
+            Err(ParseError::EmptyRequestLine)
+        ));
+        assert!(matches!(
+            parse_request(b"GET /\r\n\r\n"),
+            Err(ParseError::MissingToken)
+        ));
+        assert!(matches!(
+            parse_request(b"GET / HTTP/9.9\r\n\r\n"),
+            Err(ParseError::UnknownVersion)
+        ));
+    }
+
+    #[test]
+    fn mime_types_from_extensions() {
+        assert_eq!(mime_for("hello.html"), "text/html; charset=utf-8");
+        assert_eq!(mime_for("static/app.CSS"), "text/css; charset=utf-8");
+        assert_eq!(mime_for("app.js"), "application/javascript");
+        assert_eq!(mime_for("logo.png"), "image/png");
+        assert_eq!(mime_for("archive.tar.xyz"), "application/octet-stream");
+        assert_eq!(mime_for("Makefile"), "application/octet-stream");
+    }
+
+    #[test]
+    fn versions_are_written_as_wire_tokens() {
+        assert_eq!(version_str(Version::HTTP_09), "HTTP/0.9");
+        assert_eq!(version_str(Version::HTTP_10), "HTTP/1.0");
+        assert_eq!(version_str(Version::HTTP_11), "HTTP/1.1");
+        assert_eq!(version_str(Version::HTTP_2), "HTTP/2");
+        assert_eq!(version_str(Version::HTTP_3), "HTTP/3");
+    }
+
+    #[test]
+    fn status_lines_include_reason_phrases() {
+        let response = Response::builder().status(404).body(Vec::new()).unwrap();
+
+        let mut wire = Vec::new();
+        write_response(&mut wire, response, false).unwrap();
+
+        assert!(wire.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let status = StatusCode::from_u16(599).unwrap();
+        assert_eq!(status_line(Version::HTTP_10, status), "HTTP/1.0 599 \r\n");
+    }
+
+    #[test]
+    fn responses_carry_headers_and_content_length() {
+        let response = Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain")
+            .body(b"hello".to_vec())
+            .unwrap();
+
+        let mut wire = Vec::new();
+        write_response(&mut wire, response, false).unwrap();
+        let wire = String::from_utf8(wire).unwrap();
+
+        assert!(wire.contains("content-type: text/plain\r\n"));
+        assert!(wire.contains("content-length: 5\r\n"));
+        assert!(wire.ends_with("\r\n\r\nhello"));
+    }
+
+    /// Routes a request through `files` without a real connection.
+    fn dispatch(
+        config: &Config,
+        files: &[(&str, &str)],
+        request: Request<Vec<u8>>,
+    ) -> Response<Vec<u8>> {
+        let files = files
+            .iter()
+            .map(|&(path, file)| (String::from(path), String::from(file)))
+            .collect();
+        let sites = Sites::new(files);
+
+        response(&sites, &FileCache::new(), config, request)
+    }
+
+    /// Routes a GET for `uri` through `files` without a real connection.
+    fn respond(config: &Config, files: &[(&str, &str)], uri: &str) -> Response<Vec<u8>> {
+        let request = Request::get(uri).body(Vec::new()).unwrap();
+
+        dispatch(config, files, request)
+    }
+
+    /// A connection that reads from a fixed input and keeps what's written.
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hanging_up_gives_trickling_clients_a_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+
+            // A byte at a time, each just inside the linger timeout
+            for _ in 0..40 {
+                if stream.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        hang_up(&mut stream, &Config::default());
+
+        assert!(start.elapsed() < LINGER_LIMIT + Duration::from_millis(500));
+        drop(stream);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn connections_work_over_any_stream() {
+        let sites = Sites::new(Routes::new());
+        sites.default.route("/", |request| {
+            Response::new(format!("hi {}", request.uri().query().unwrap_or("")))
+        });
+
+        let mut stream = MemoryStream {
+            input: Cursor::new(b"GET /?a HTTP/1.1\r\n\r\nGET /?b HTTP/1.1\r\n\r\n".to_vec()),
+            output: Vec::new(),
+        };
+        let stopped = AtomicBool::new(false);
+
+        let ending = handle_connection(
+            &sites,
+            &FileCache::new(),
+            &Config::default(),
+            &stopped,
+            &mut stream,
+            None,
+        )
+        .unwrap();
+        assert!(matches!(ending, Ending::Finished));
+
+        let output = String::from_utf8(stream.output).unwrap();
+        let first = output.find("\r\n\r\nhi a").unwrap();
+        assert!(output[first..].contains("\r\n\r\nhi b"));
+    }
+
+    #[test]
+    fn handlers_can_be_routed_by_method() {
+        let sites = Sites::new(Routes::new());
+        sites
+            .default
+            .get("/items", |_| Response::new(String::from("list")));
+        sites
+            .default
+            .post("/items", |_| Response::new(String::from("created")));
+        let config = Config::default();
+
+        let request = Request::get("/items").body(Vec::new()).unwrap();
+        let routed = response(&sites, &FileCache::new(), &config, request);
+        assert_eq!(routed.body(), b"list");
+
+        let request = Request::post("/items").body(Vec::new()).unwrap();
+        let routed = response(&sites, &FileCache::new(), &config, request);
+        assert_eq!(routed.body(), b"created");
+
+        let request = Request::head("/items").body(Vec::new()).unwrap();
+        let routed = response(&sites, &FileCache::new(), &config, request);
+        assert_eq!(routed.status(), 200);
+    }
+
+    #[test]
+    fn unrouted_methods_are_not_allowed() {
+        let sites = Sites::new(Routes::new());
+        sites
+            .default
+            .route_method(Method::PUT, "/items", |_| Response::new(String::new()));
+        sites
+            .default
+            .get("/items", |_| Response::new(String::new()));
+
+        let request = Request::delete("/items").body(Vec::new()).unwrap();
+        let response = response(&sites, &FileCache::new(), &Config::default(), request);
+
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD, PUT");
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(normalize_path("/a//b"), "/a/b");
+        assert_eq!(normalize_path("/a/./b/"), "/a/b/");
+        assert_eq!(normalize_path("//a/."), "/a/");
+        assert_eq!(normalize_path("/a/../b"), "/a/../b");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("//"), "/");
+    }
+
+    #[test]
+    fn normalized_paths_are_routed() {
+        let sites = Sites::new(Routes::new());
+        sites
+            .default
+            .route("/a/b", |_| Response::new(String::from("b")));
+
+        let mut config = Config::default();
+        for uri in &["/a//b", "/a/./b", "//a/b"] {
+            let request = Request::get(*uri).body(Vec::new()).unwrap();
+            let routed = response(&sites, &FileCache::new(), &config, request);
+            assert_eq!(routed.body(), b"b");
+        }
+
+        config.path_normalization = PathNormalization::Redirect;
+        let request = Request::get("/a/./b?x=1").body(Vec::new()).unwrap();
+        let routed = response(&sites, &FileCache::new(), &config, request);
+        assert_eq!(routed.status(), 301);
+        assert_eq!(routed.headers()[LOCATION], "/a/b?x=1");
+
+        config.path_normalization = PathNormalization::Off;
+        let request = Request::get("/a//b").body(Vec::new()).unwrap();
+        let routed = response(&sites, &FileCache::new(), &config, request);
+        assert_eq!(routed.status(), 404);
+    }
+
+    /// Routes a GET for `uri` to handlers at `/about` and `/docs/`.
+    fn respond_slashed(mode: TrailingSlash, uri: &str) -> Response<Vec<u8>> {
+        let sites = Sites::new(Routes::new());
+        sites
+            .default
+            .route("/about", |_| Response::new(String::from("about")));
+        sites
+            .default
+            .route("/docs/", |_| Response::new(String::from("docs")));
+
+        let config = Config {
+            trailing_slash: mode,
+            ..Config::default()
+        };
+        let request = Request::get(uri).body(Vec::new()).unwrap();
+
+        response(&sites, &FileCache::new(), &config, request)
+    }
+
+    #[test]
+    fn strict_trailing_slashes() {
+        assert_eq!(
+            respond_slashed(TrailingSlash::Strict, "/about").status(),
+            200
+        );
+        assert_eq!(
+            respond_slashed(TrailingSlash::Strict, "/about/").status(),
+            404
+        );
+        assert_eq!(
+            respond_slashed(TrailingSlash::Strict, "/docs").status(),
+            404
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_can_redirect() {
+        let response = respond_slashed(TrailingSlash::Redirect, "/about/?x=1");
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.headers()[LOCATION], "/about?x=1");
+
+        let response = respond_slashed(TrailingSlash::Redirect, "/docs");
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.headers()[LOCATION], "/docs/");
+
+        assert_eq!(
+            respond_slashed(TrailingSlash::Redirect, "/about").status(),
+            200
+        );
+    }
+
+    #[test]
+    fn trailing_slashes_can_merge() {
+        assert_eq!(
+            respond_slashed(TrailingSlash::Merge, "/about/").body(),
+            b"about"
+        );
+        assert_eq!(
+            respond_slashed(TrailingSlash::Merge, "/about").body(),
+            b"about"
+        );
+        assert_eq!(
+            respond_slashed(TrailingSlash::Merge, "/docs").body(),
+            b"docs"
+        );
+        assert_eq!(
+            respond_slashed(TrailingSlash::Merge, "/other/").status(),
+            404
+        );
+    }
+
+    /// Feeds `input` through a connection serving an echo handler at `/echo`,
+    /// and gives back everything written.
+    fn converse(config: &Config, input: &[u8]) -> String {
+        let sites = Sites::new(Routes::new());
+        sites.default.route("/echo", |request| {
+            Response::new(String::from_utf8_lossy(request.body()).into_owned())
+        });
+
+        let mut stream = MemoryStream {
+            input: Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        };
+        let stopped = AtomicBool::new(false);
+
+        handle_connection(
+            &sites,
+            &FileCache::new(),
+            config,
+            &stopped,
+            &mut stream,
+            None,
+        )
+        .unwrap();
+
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    #[test]
+    fn clients_expecting_continue_are_told_to_go_ahead() {
+        let output = converse(
+            &Config::default(),
+            b"POST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+        );
+
+        assert!(output.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nhello"));
+
+        let config = Config {
+            max_request_bytes: 64,
+            ..Config::default()
+        };
+        let output = converse(
+            &config,
+            b"POST /echo HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 500\r\n\r\n",
+        );
+
+        assert!(output.starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn disallowed_methods_list_what_is_allowed() {
+        let request = Request::delete("/").body(Vec::new()).unwrap();
+
+        let response = dispatch(&Config::default(), &[("/", "hello.html")], request);
+
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD, POST");
+    }
+
+    #[test]
+    fn paths_are_percent_decoded() {
+        let config = Config::default();
+
+        let response = respond(&config, &[("/hello world", "hello.html")], "/hello%20world");
+        assert_eq!(response.status(), 200);
+
+        let response = respond(&config, &[("/a/b", "hello.html")], "/a%2Fb");
+        assert_eq!(response.status(), 200);
+
+        let response = respond(&config, &[("/a/b", "hello.html")], "/a%zzb");
+        assert_eq!(response.status(), 400);
+
+        let response = respond(&config, &[("/a/b", "hello.html")], "/a%+fb");
+        assert_eq!(response.status(), 400);
+
+        let response = respond(&config, &[("/a/b", "hello.html")], "/a%-1b");
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn missing_not_found_page_falls_back() {
+        let mut config = Config::default();
+        config
+            .error_pages
+            .insert(StatusCode::NOT_FOUND, String::from("does-not-exist.html"));
+
+        let response = respond(&config, &[], "/missing");
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.body(), DEFAULT_NOT_FOUND_BODY.as_bytes());
+    }
+
+    #[test]
+    fn routes_can_be_added_while_running() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        assert!(get(&addr, "/hello").contains(" 404 "));
+
+        server.add_route("/hello", "hello.html");
+        assert!(get(&addr, "/hello").contains(" 200 "));
+
+        server.remove_route("/hello");
+        assert!(get(&addr, "/hello").contains(" 404 "));
+    }
+
+    #[test]
+    fn handlers_compute_responses() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        server.route("/health", |request| {
+            Response::builder()
+                .status(200)
+                .body(format!("ok {}", request.method()))
+                .unwrap()
+        });
+
+        assert!(get(&addr, "/health").ends_with("\r\n\r\nok GET"));
+    }
+
+    /// Creates an empty directory unique to a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("server-test-{}-{}", std::process::id(), name));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn directories_are_served_under_a_prefix() {
+        let root = temp_dir("serve-dir");
+        fs::create_dir_all(root.join("css")).unwrap();
+        fs::write(root.join("css/app.css"), "body {}").unwrap();
+
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.serve_dir("/static/", root.to_str().unwrap());
+
+        let response = get(&addr, "/static/css/app.css");
+        assert!(response.contains(" 200 "));
+        assert!(response.contains("text/css"));
+        assert!(response.ends_with("body {}"));
+
+        assert!(get(&addr, "/static/css/missing.css").contains(" 404 "));
+        assert!(get(&addr, "/static/../Cargo.toml").contains(" 403 "));
+        // Collapsed to a path under the root, where there's nothing
+        assert!(get(&addr, "/static//etc/passwd").contains(" 404 "));
+    }
+
+    #[test]
+    fn directories_serve_their_index() {
+        let root = temp_dir("index");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("index.html"), "home").unwrap();
+        fs::write(root.join("docs/start.html"), "docs").unwrap();
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_index_file("start.html");
+        server.serve_dir("/static", root.to_str().unwrap());
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/static/docs/").ends_with("\r\n\r\ndocs"));
+        assert!(get(&addr, "/static/empty/").starts_with("HTTP/1.1 404 "));
+
+        // Without being told otherwise, index.html is used
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.serve_dir("/static", root.to_str().unwrap());
+
+        assert!(get(&addr, "/static/").ends_with("\r\n\r\nhome"));
+    }
+
+    #[test]
+    fn directories_cannot_be_escaped() {
+        let base = temp_dir("escape");
+        let root = base.join("public");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.serve_dir("/static", root.to_str().unwrap());
+
+        for path in &[
+            "/static/../secret.txt",
+            "/static/..%2fsecret.txt",
+            "/static/%2e%2e/secret.txt",
+            "/static/%2e%2e%2fsecret.txt",
+        ] {
+            assert!(get(&addr, path).starts_with("HTTP/1.1 403 "), "{}", path);
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+
+            assert!(get(&addr, "/static/link.txt").starts_with("HTTP/1.1 403 "));
+        }
+    }
+
+    #[test]
+    fn binary_files_are_served_as_is() {
+        // A 1x1 PNG, which is nowhere near valid UTF-8
+        let png: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0\x1f\x15\xc4\x89\0\0\0\rIDATx\x9cc\xf8\xff\xff?\0\x05\xfe\x02\xfe\xa7\x35\x81\x84\0\0\0\0IEND\xaeB`\x82";
+
+        let root = temp_dir("binary");
+        fs::write(root.join("pixel.png"), png).unwrap();
+
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/pixel.png", root.join("pixel.png").to_str().unwrap());
+
+        let response = send(
+            &addr,
+            b"GET /pixel.png HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(response.starts_with(b"HTTP/1.1 200 "));
+        assert_eq!(body(&response), png);
+    }
+
+    #[test]
+    fn handlers_see_the_peer_address() {
+        let server = WebServer::new(2, Routes::new());
+        server.route("/", |request| {
+            let peer = request.extensions().get::<SocketAddr>();
+
+            Response::new(peer.map_or_else(String::new, |peer| peer.ip().to_string()))
+        });
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/").ends_with("\r\n\r\n127.0.0.1"));
+    }
+
+    #[test]
+    fn shutdown_stops_the_server() {
+        let addr = free_addr();
+
+        let server = Arc::new(WebServer::new(2, Routes::new()));
+        let running = Arc::clone(&server);
+        let bind = addr.clone();
+        let handle = thread::spawn(move || running.start(&bind).is_ok());
+
+        assert!(get(&addr, "/").contains(" 404 "));
+
+        server.shutdown();
+        assert!(handle.join().unwrap());
+        assert!(TcpStream::connect(&addr).is_err());
+    }
+
+    #[cfg(feature = "signals")]
+    #[test]
+    fn signals_stop_the_server() {
+        let addr = free_addr();
+
+        let server = Arc::new(WebServer::new(2, Routes::new()));
+        server.shutdown_on_signals().unwrap();
+
+        let running = Arc::clone(&server);
+        let bind = addr.clone();
+        let handle = thread::spawn(move || running.start(&bind).is_ok());
+
+        assert!(get(&addr, "/").contains(" 404 "));
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        assert!(handle.join().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_sockets() {
+        use std::os::unix::net::UnixStream;
+
+        let path = temp_dir("unix-socket").join("server.sock");
+        let path = path.to_str().unwrap().to_string();
+
+        let server = Arc::new(WebServer::new(2, Routes::new()));
+        server.route("/", |_| Response::new(String::from("hi")));
+
+        let running = Arc::clone(&server);
+        let bind = path.clone();
+        let handle = thread::spawn(move || running.start_unix(&bind).is_ok());
+
+        let mut stream = loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nhi"));
+
+        server.shutdown();
+        assert!(handle.join().unwrap());
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn servers_listen_on_several_addresses() {
+        let addrs = [free_addr(), free_addr()];
+
+        let server = Arc::new(WebServer::new(2, Routes::new()));
+        server.route("/", |_| Response::new(String::from("hi")));
+
+        let running = Arc::clone(&server);
+        let bind = addrs.clone();
+        let handle = thread::spawn(move || {
+            let addrs: Vec<&str> = bind.iter().map(String::as_str).collect();
+            running.start_multi(&addrs).is_ok()
+        });
+
+        for addr in &addrs {
+            assert!(get(addr, "/").ends_with("\r\n\r\nhi"));
+        }
+
+        server.shutdown();
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn binding_fails_if_any_address_is_taken() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+        let free = free_addr();
+
+        let server = WebServer::new(2, Routes::new());
+        let err = server.start_multi(&[&free, &taken]).unwrap_err();
+
+        match err {
+            ServerError::Bind(failures) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, taken);
+            }
+            err => panic!("expected a bind error, got {}", err),
+        }
+    }
+
+    /// Fails to accept every connection.
+    struct BrokenListener;
+
+    impl Listener for BrokenListener {
+        type Stream = TcpStream;
+
+        fn accept(&self) -> io::Result<TcpStream> {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "broken"))
+        }
+    }
+
+    #[test]
+    fn start_errors_can_be_told_apart() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = taken.local_addr().unwrap().to_string();
+
+        let server = WebServer::new(2, Routes::new());
+        match server.start(&taken) {
+            Err(ServerError::Bind(failures)) => assert_eq!(failures[0].0, taken),
+            result => panic!("expected a bind error, got {:?}", result),
+        }
+
+        match server.serve(&[BrokenListener]) {
+            Err(ServerError::Io(err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied)
+            }
+            result => panic!("expected an IO error, got {:?}", result),
+        }
+
+        let server = WebServer::new(0, Routes::new());
+        match server.start(&free_addr()) {
+            Err(ServerError::PoolCreation(PoolCreationError::NoThreads)) => {}
+            result => panic!("expected a pool creation error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn clients_over_the_rate_limit_are_refused() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_rate_limit(2, Duration::from_secs(60));
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/").contains(" 404 "));
+        assert!(get(&addr, "/").contains(" 404 "));
+
+        let response = get(&addr, "/");
+        assert!(response.starts_with("HTTP/1.1 429 "));
+        assert!(response.contains("retry-after: 60\r\n"));
+    }
+
+    #[test]
+    fn denied_clients_are_forbidden() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.deny_ip("127.0.0.0/8").unwrap();
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/").starts_with("HTTP/1.1 403 "));
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.allow_ip("127.0.0.1/32").unwrap();
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn metrics_count_requests() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.route("/ok", |_| Response::new(String::new()));
+        server.serve_metrics("/metrics");
+
+        get(&addr, "/ok");
+        get(&addr, "/ok");
+        get(&addr, "/missing");
+
+        let metrics = get(&addr, "/metrics");
+        assert!(metrics.contains("text/plain; version=0.0.4"));
+        assert!(metrics.contains("http_requests_total 4\n"));
+        assert!(metrics.contains("http_responses_total{class=\"2xx\"} 2\n"));
+        assert!(metrics.contains("http_responses_total{class=\"4xx\"} 1\n"));
+        assert!(metrics.contains("http_active_connections 1\n"));
+    }
+
+    #[test]
+    fn panicking_handlers_are_internal_errors() {
+        let (server, addr) = spawn(WebServer::new(1, Routes::new()));
+        server.route("/boom", |_| panic!("boom"));
+        server.route("/ok", |_| Response::new(String::from("fine")));
+
+        assert!(get(&addr, "/boom").starts_with("HTTP/1.1 500 "));
+
+        // The only worker is still around to answer
+        assert!(get(&addr, "/ok").ends_with("\r\n\r\nfine"));
+    }
+
+    #[test]
+    fn error_pages_replace_server_errors() {
+        let root = temp_dir("error-pages");
+        fs::write(root.join("500.html"), "<h1>oops</h1>").unwrap();
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_error_page(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            root.join("500.html").to_str().unwrap(),
+        );
+        server.set_error_page(StatusCode::FORBIDDEN, "does-not-exist.html");
+        server.serve_dir("/static", root.to_str().unwrap());
+        let (server, addr) = spawn(server);
+        server.route("/boom", |_| panic!("boom"));
+
+        let response = get(&addr, "/boom");
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        assert!(response.contains("content-type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>oops</h1>"));
+
+        // Unreadable pages fall back to the built-in body
+        assert!(get(&addr, "/static/../x").ends_with("\r\n\r\nForbidden"));
+    }
+
+    #[test]
+    fn shutdown_lets_requests_finish() {
+        let addr = free_addr();
+        let started = Arc::new(AtomicBool::new(false));
+
+        let server = Arc::new(WebServer::new(2, Routes::new()));
+        let handler_started = Arc::clone(&started);
+        server.route("/slow", move |_| {
+            handler_started.store(true, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+
+            Response::new(String::from("done"))
+        });
+
+        let running = Arc::clone(&server);
+        let bind = addr.clone();
+        let handle = thread::spawn(move || running.start(&bind).is_ok());
+
+        let client = thread::spawn({
+            let addr = addr.clone();
+            move || get(&addr, "/slow")
+        });
+
+        while !started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        server.shutdown();
+        assert!(handle.join().unwrap());
+        assert!(client.join().unwrap().ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    fn shutdown_leaves_stuck_handlers_behind() {
+        let addr = free_addr();
+        let (started, running) = mpsc::channel();
+        let (release, stuck) = mpsc::channel::<()>();
+        let stuck = Mutex::new(stuck);
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_shutdown_grace_period(Duration::from_millis(50));
+        let server = Arc::new(server);
+        server.route("/stuck", move |_| {
+            started.send(()).unwrap();
+            // Not waiting on the connection, so cutting it off won't help
+            let _ = stuck.lock().unwrap().recv();
+
+            Response::new(String::new())
+        });
+
+        let serving = Arc::clone(&server);
+        let bind = addr.clone();
+        let handle = thread::spawn(move || serving.start(&bind).is_ok());
+
+        let _client = thread::spawn({
+            let addr = addr.clone();
+            move || get(&addr, "/stuck")
+        });
+        running.recv().unwrap();
+
+        let start = Instant::now();
+        server.shutdown();
+        assert!(handle.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn built_servers_use_their_options() {
+        let server = WebServer::builder()
+            .thread_limit(1)
+            .server_name("built/1.0")
+            .max_request_bytes(64)
+            .build();
+        let (_server, addr) = spawn(server);
+
+        assert!(get(&addr, "/").contains("server: built/1.0"));
+
+        let long = format!("/{}", "a".repeat(100));
+        assert!(get(&addr, &long).starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn busy_servers_refuse_connections() {
+        let release = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_max_connections(1);
+        let (handler_started, handler_release) = (Arc::clone(&started), Arc::clone(&release));
+        server.route("/slow", move |_| {
+            handler_started.store(true, Ordering::SeqCst);
+            while !handler_release.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+
+            Response::new(String::from("done"))
+        });
+        let (_server, addr) = spawn(server);
+
+        let slow = thread::spawn({
+            let addr = addr.clone();
+            move || get(&addr, "/slow")
+        });
+        while !started.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let response = get(&addr, "/");
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert!(response.contains("retry-after: 1"));
+
+        release.store(true, Ordering::SeqCst);
+        assert!(slow.join().unwrap().ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    fn refusing_connections_never_waits_on_the_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            for _ in 0..20 {
+                if stream.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let start = Instant::now();
+        refuse_busy(&Config::default(), stream);
+
+        assert!(start.elapsed() < REFUSAL_TIMEOUT * 3);
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn connections_are_kept_alive() {
+        let (_server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        let response = send(
+            &addr,
+            b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        assert_eq!(response.matches(" 404 ").count(), 2);
+        assert_eq!(response.matches("connection: close").count(), 1);
+    }
+
+    #[test]
+    fn http_1_0_connections_close_by_default() {
+        let (_server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        let response = send(&addr, b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n");
+        let response = String::from_utf8(response).unwrap();
+
+        assert_eq!(response.matches(" 404 ").count(), 1);
+        assert!(response.contains("connection: close"));
+
+        let response = send(
+            &addr,
+            b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        assert_eq!(response.matches(" 404 ").count(), 2);
+        assert!(response.contains("connection: keep-alive"));
+    }
+
+    #[test]
+    fn connections_close_when_asked() {
+        let (_server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        let response = send(
+            &addr,
+            b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+        );
+        let response = String::from_utf8(response).unwrap();
+
+        assert_eq!(response.matches(" 404 ").count(), 1);
+        assert!(response.contains("connection: close"));
+    }
+
+    #[test]
+    fn connections_close_after_max_requests() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_max_requests_per_connection(1);
+        let (_server, addr) = spawn(server);
+
+        let response = send(&addr, b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+        let response = String::from_utf8(response).unwrap();
+
+        assert_eq!(response.matches(" 404 ").count(), 1);
+        assert!(response.contains("connection: close"));
+    }
+
+    #[test]
+    fn clients_hanging_up_without_a_request_are_ignored() {
+        let (_server, addr) = spawn(WebServer::new(1, Routes::new()));
+
+        // Like a health check probe or port scan
+        for _ in 0..10 {
+            drop(connect(&addr));
+        }
+
+        let mut stream = connect(&addr);
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+
+        // The only worker is still around to answer
+        assert!(get(&addr, "/").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn slow_clients_time_out() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_read_timeout(Some(Duration::from_millis(50)));
+        let (_server, addr) = spawn(server);
+
+        let response = send(&addr, b"GET / HTTP/1.1\r\n");
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 408 "));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_max_request_bytes(1024);
+        let (_server, addr) = spawn(server);
+
+        let response = send(&addr, b"POST / HTTP/1.1\r\nContent-Length: 4096\r\n\r\n");
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn unclear_body_lengths_are_refused() {
+        let config = Config::default();
+        let read = |headers: &str| {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\nhello", headers);
+            read_request(&mut BufReader::new(Cursor::new(raw.into_bytes())), &config)
+        };
+
+        assert!(read("Content-Length: 5\r\n").is_ok());
+        assert!(read("Content-Length: 5\r\nContent-Length: 5\r\n").is_ok());
+
+        for headers in &[
+            "Content-Length: abc\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: 5, 5\r\n",
+            "Content-Length:\r\n",
+            "Content-Length: 5\r\nContent-Length: 3\r\n",
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+        ] {
+            let err = read(headers).unwrap_err();
+            assert!(matches!(err, ReadError::AmbiguousLength), "{:?}", headers);
+        }
+
+        let err = read("Transfer-Encoding: chunked\r\n").unwrap_err();
+        assert!(matches!(err, ReadError::TransferEncoding));
+
+        // Neither is answered and then carried on from
+        let (_server, addr) = spawn(WebServer::new(2, Routes::new()));
+        let cases: &[(&[u8], &str)] = &[
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 30\r\n\r\n",
+                "HTTP/1.1 400 ",
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 501 ",
+            ),
+        ];
+        for (request, status) in cases {
+            let response = String::from_utf8(send(&addr, request)).unwrap();
+
+            assert!(response.starts_with(status), "{}", response);
+            assert!(response.contains("connection: close\r\n"));
+            assert_eq!(response.matches("HTTP/1.1 ").count(), 1);
+        }
+    }
+
+    #[test]
+    fn too_many_or_too_long_headers_are_refused() {
+        let config = Config {
+            max_headers: 3,
+            max_header_bytes: 32,
+            ..Config::default()
+        };
+        let read =
+            |raw: String| read_request(&mut BufReader::new(Cursor::new(raw.into_bytes())), &config);
+
+        let headers = "X-A: 1\r\n".repeat(3);
+        assert!(read(format!("GET / HTTP/1.1\r\n{}\r\n", headers)).is_ok());
+
+        let headers = "X-A: 1\r\n".repeat(4);
+        let err = read(format!("GET / HTTP/1.1\r\n{}\r\n", headers)).unwrap_err();
+        assert!(matches!(err, ReadError::HeadersTooLarge));
+
+        let header = format!("X-Long: {}\r\n", "a".repeat(100));
+        let err = read(format!("GET / HTTP/1.1\r\n{}\r\n", header)).unwrap_err();
+        assert!(matches!(err, ReadError::HeadersTooLarge));
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_header_limits(100, 32);
+        let (_server, addr) = spawn(server);
+
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n", header);
+        let response = String::from_utf8(send(&addr, request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[test]
+    fn head_requests_get_no_body() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/", "hello.html");
+
+        let response = send(&addr, b"HEAD / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let response = String::from_utf8(response).unwrap();
+        let length = fs::read("hello.html").unwrap().len();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("content-length: {}\r\n", length)));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    /// Splits the body off of a raw response.
+    fn body(response: &[u8]) -> &[u8] {
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+
+        &response[end + 4..]
+    }
+
+    #[test]
+    fn text_is_gzipped_when_accepted() {
+        let root = temp_dir("gzip");
+        let page = "<p>Hello, compression!</p>\n".repeat(200);
+        fs::write(root.join("big.html"), &page).unwrap();
+
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/big", root.join("big.html").to_str().unwrap());
+
+        let response = send(
+            &addr,
+            b"GET /big HTTP/1.1\r\nAccept-Encoding: gzip, br\r\nConnection: close\r\n\r\n",
+        );
+        assert!(String::from_utf8_lossy(&response).contains("content-encoding: gzip\r\n"));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(body(&response))
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, page);
+
+        // Without the header the page goes out as is
+        let plain = get(&addr, "/big");
+        assert!(plain.ends_with(&page));
+        assert!(plain.contains("vary: accept-encoding\r\n"));
+
+        // Each version has its own ETag, and either can be revalidated
+        let etag = |response: &str| {
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("etag: "))
+                .map(String::from)
+                .unwrap()
+        };
+        let gzipped_etag = etag(&String::from_utf8_lossy(&response));
+        let plain_etag = etag(&plain);
+        assert_eq!(gzipped_etag, gzip_etag(&plain_etag));
+        assert!(gzipped_etag.ends_with("-gzip\""));
+
+        let revalidate = format!(
+            "GET /big HTTP/1.1\r\nAccept-Encoding: gzip\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n",
+            gzipped_etag
+        );
+        let response = String::from_utf8(send(&addr, revalidate.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 304 "));
+        assert!(response.contains(&format!("etag: {}\r\n", gzipped_etag)));
+    }
+
+    #[test]
+    fn gzip_can_be_refused() {
+        let request = Request::get("/")
+            .header(ACCEPT_ENCODING, "gzip;q=0, deflate")
+            .body(())
+            .unwrap();
+
+        assert!(!accepts_gzip(&request));
+    }
+
+    #[test]
+    fn unchanged_files_are_not_modified() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/", "hello.html");
+
+        let response = get(&addr, "/");
+        let etag = response
+            .lines()
+            .find_map(|line| line.strip_prefix("etag: "))
+            .unwrap();
+
+        let request = format!(
+            "GET / HTTP/1.1\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n",
+            etag
+        );
+        let response = String::from_utf8(send(&addr, request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 304 "));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let request = "GET / HTTP/1.1\r\nIf-None-Match: \"stale\"\r\nConnection: close\r\n\r\n";
+        let response = String::from_utf8(send(&addr, request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn files_not_modified_since_are_not_sent() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/", "hello.html");
+
+        let response = get(&addr, "/");
+        let modified = response
+            .lines()
+            .find_map(|line| line.strip_prefix("last-modified: "))
+            .unwrap();
+
+        let request = format!(
+            "GET / HTTP/1.1\r\nIf-Modified-Since: {}\r\nConnection: close\r\n\r\n",
+            modified
+        );
+        let response = String::from_utf8(send(&addr, request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 304 "));
+
+        let request = "GET / HTTP/1.1\r\nIf-Modified-Since: whenever\r\nConnection: close\r\n\r\n";
+        let response = String::from_utf8(send(&addr, request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn responses_carry_date_and_server() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.set_server_name("test-server/1.0");
+        let (_server, addr) = spawn(server);
+
+        let response = get(&addr, "/");
+        let date = response
+            .lines()
+            .find_map(|line| line.strip_prefix("date: "))
+            .unwrap();
+
+        assert!(parse_http_date(date).is_some());
+        assert!(response.contains("server: test-server/1.0\r\n"));
+    }
+
+    /// Reassembles a chunked body.
+    fn dechunk(mut body: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+
+        loop {
+            let line_end = body
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .unwrap();
+            let size = std::str::from_utf8(&body[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            body = &body[line_end + 2..];
+
+            if size == 0 {
+                return decoded;
+            }
+
+            decoded.extend_from_slice(&body[..size]);
+            body = &body[size + 2..];
+        }
+    }
+
+    #[test]
+    fn large_files_are_streamed() {
+        let root = temp_dir("stream");
+        let contents: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("large.bin"), &contents).unwrap();
+
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/large", root.join("large.bin").to_str().unwrap());
+
+        let response = send(&addr, b"GET /large HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let head = String::from_utf8_lossy(&response[..response.len() - body(&response).len()]);
+
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        assert!(!head.contains("content-length"));
+        assert!(dechunk(body(&response)) == contents);
+
+        let request = b"GET /large HTTP/1.1\r\nRange: bytes=1000-1999\r\nConnection: close\r\n\r\n";
+        let response = send(&addr, request);
+
+        assert!(response.starts_with(b"HTTP/1.1 206 "));
+        assert!(dechunk(body(&response)) == contents[1000..2000]);
+    }
+
+    #[test]
+    fn ranges_get_partial_content() {
+        let root = temp_dir("range");
+        fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        let file = root.join("digits.txt");
+        let files = [("/digits", file.to_str().unwrap())];
+
+        let ranged = |range: &str| {
+            let request = Request::get("/digits")
+                .header(RANGE, range)
+                .body(Vec::new())
+                .unwrap();
+
+            dispatch(&Config::default(), &files, request)
+        };
+
+        let response = ranged("bytes=2-4");
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.body(), b"234");
+
+        let response = ranged("bytes=-3");
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(response.body(), b"789");
+
+        let response = ranged("bytes=20-30");
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = respond(&Config::default(), &files, "/digits");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+    }
+
+    #[test]
+    fn redirects_send_clients_elsewhere() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+        server.add_route("/old", "hello.html");
+        server.redirect("/old", "/new", true);
+        server.redirect("/moment", "https://example.com/", false);
+
+        let response = get(&addr, "/old");
+        assert!(response.starts_with("HTTP/1.1 301 "));
+        assert!(response.contains("location: /new\r\n"));
+
+        let response = get(&addr, "/moment");
+        assert!(response.starts_with("HTTP/1.1 302 "));
+        assert!(response.contains("location: https://example.com/\r\n"));
+    }
+
+    #[test]
+    fn pattern_routes_capture_path_params() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        let echo = |name: &'static str| {
+            move |request: &Request<Vec<u8>>| {
+                let params = request.extensions().get::<PathParams>().unwrap();
+
+                Response::new(format!("{}={}", name, params.get(name).unwrap()))
+            }
+        };
+        server.route("/users/:id", echo("id"));
+        server.route("/users/*rest", echo("rest"));
+
+        assert!(get(&addr, "/users/42").ends_with("id=42"));
+        assert!(get(&addr, "/users/42/posts/7").ends_with("rest=42/posts/7"));
+    }
+
+    #[test]
+    fn regex_routes_capture_named_groups() {
+        let (server, addr) = spawn(WebServer::new(2, Routes::new()));
+
+        let pattern = regex::Regex::new(r"^/posts/(?P<year>\d{4})/(?P<slug>[a-z-]+)$").unwrap();
+        server.route_regex(pattern, |request| {
+            let params = request.extensions().get::<PathParams>().unwrap();
+
+            Response::new(format!(
+                "{} {}",
+                params.get("year").unwrap(),
+                params.get("slug").unwrap()
+            ))
+        });
+
+        assert!(get(&addr, "/posts/2020/hello-world").ends_with("2020 hello-world"));
+        assert!(get(&addr, "/posts/twenty/hello-world").contains(" 404 "));
+    }
+
+    #[test]
+    fn middleware_wraps_routing() {
+        struct Tag;
+
+        impl Middleware for Tag {
+            fn handle(&self, request: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+                let mut response = next.run(request);
+                response
+                    .headers_mut()
+                    .insert("X-Tag", HeaderValue::from_static("tagged"));
+
+                response
+            }
+        }
+
+        let mut server = WebServer::new(2, Routes::new());
+        server.add_middleware(Logger);
+        server.add_middleware(Tag);
+        let (_server, addr) = spawn(server);
+
+        let response = get(&addr, "/");
+        assert!(response.contains(" 404 "));
+        assert!(response.contains("x-tag: tagged\r\n"));
+    }
+
+    #[test]
+    fn access_log_lines() {
+        let peer = "127.0.0.1:4000".parse().ok();
+        let response = Response::new(b"hello".to_vec());
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        let line = access_log_line(
+            peer,
+            "GET /index.html HTTP/1.1",
+            &response,
+            time,
+            Duration::from_millis(3),
+        );
+
+        assert_eq!(
+            line,
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html HTTP/1.1\" 200 5 3ms"
+        );
+    }
+
+    #[test]
+    fn hosts_get_their_own_routes() {
+        let mut server = WebServer::new(2, Routes::new());
+        server.route("/", |_| Response::new(String::from("default")));
+        server
+            .host("a.test")
+            .route("/", |_| Response::new(String::from("site a")));
+        server
+            .host("b.test")
+            .route("/", |_| Response::new(String::from("site b")));
+
+        let request = |host: &str| {
+            Request::get("/")
+                .header("Host", host)
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        let config = server.config.clone();
+        let sites = Arc::clone(&server.sites);
+        let cache = FileCache::new();
+
+        let answer = |host| response(&sites, &cache, &config, request(host)).into_body();
+        assert_eq!(answer("a.test"), b"site a");
+        assert_eq!(answer("B.test:7878"), b"site b");
+        assert_eq!(answer("c.test"), b"default");
+
+        server.set_strict_hosts(true);
+        let config = server.config.clone();
+
+        let answer = response(&sites, &cache, &config, request("c.test"));
+        assert_eq!(answer.status(), StatusCode::NOT_FOUND);
     }
 }
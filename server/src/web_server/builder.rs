@@ -0,0 +1,179 @@
+use http::StatusCode;
+use std::time::Duration;
+
+use super::{Middleware, PathNormalization, Routes, TrailingSlash, WebServer};
+
+/// Configures a `WebServer` one option at a time.
+///
+/// Made with `WebServer::builder`. Anything left unset keeps the default
+/// documented on the matching `WebServer` setter.
+pub struct WebServerBuilder {
+    server: WebServer,
+}
+
+impl WebServerBuilder {
+    pub(super) fn new() -> WebServerBuilder {
+        WebServerBuilder {
+            server: WebServer::new(4, Routes::new()),
+        }
+    }
+
+    /// Sets how many threads handle connections. Defaults to 4.
+    pub fn thread_limit(mut self, thread_limit: usize) -> WebServerBuilder {
+        self.server.thread_limit = thread_limit;
+        self
+    }
+
+    /// Maps each path onto a file, as is done with `WebServer::add_route`.
+    pub fn routes(self, routes: Routes) -> WebServerBuilder {
+        for (path, file) in &routes {
+            self.server.add_route(path, file);
+        }
+        self
+    }
+
+    /// See `WebServer::set_not_found_page`.
+    pub fn not_found_page(mut self, path: &str) -> WebServerBuilder {
+        self.server.set_not_found_page(path);
+        self
+    }
+
+    /// See `WebServer::set_error_page`.
+    pub fn error_page(mut self, status: StatusCode, path: &str) -> WebServerBuilder {
+        self.server.set_error_page(status, path);
+        self
+    }
+
+    /// See `WebServer::set_index_file`.
+    pub fn index_file(mut self, name: &str) -> WebServerBuilder {
+        self.server.set_index_file(name);
+        self
+    }
+
+    /// See `WebServer::set_max_requests_per_connection`.
+    pub fn max_requests_per_connection(mut self, max: usize) -> WebServerBuilder {
+        self.server.set_max_requests_per_connection(max);
+        self
+    }
+
+    /// See `WebServer::set_read_timeout`.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> WebServerBuilder {
+        self.server.set_read_timeout(timeout);
+        self
+    }
+
+    /// See `WebServer::set_max_request_bytes`.
+    pub fn max_request_bytes(mut self, max: usize) -> WebServerBuilder {
+        self.server.set_max_request_bytes(max);
+        self
+    }
+
+    /// See `WebServer::set_header_limits`.
+    pub fn header_limits(
+        mut self,
+        max_headers: usize,
+        max_header_bytes: usize,
+    ) -> WebServerBuilder {
+        self.server.set_header_limits(max_headers, max_header_bytes);
+        self
+    }
+
+    /// See `WebServer::set_buffer_size`.
+    pub fn buffer_size(mut self, size: usize) -> WebServerBuilder {
+        self.server.set_buffer_size(size);
+        self
+    }
+
+    /// See `WebServer::set_server_name`.
+    pub fn server_name(mut self, name: &str) -> WebServerBuilder {
+        self.server.set_server_name(name);
+        self
+    }
+
+    /// See `WebServer::set_access_log`.
+    pub fn access_log(mut self, enabled: bool) -> WebServerBuilder {
+        self.server.set_access_log(enabled);
+        self
+    }
+
+    /// See `WebServer::set_strict_hosts`.
+    pub fn strict_hosts(mut self, strict: bool) -> WebServerBuilder {
+        self.server.set_strict_hosts(strict);
+        self
+    }
+
+    /// See `WebServer::set_trailing_slash`.
+    pub fn trailing_slash(mut self, mode: TrailingSlash) -> WebServerBuilder {
+        self.server.set_trailing_slash(mode);
+        self
+    }
+
+    /// See `WebServer::set_path_normalization`.
+    pub fn path_normalization(mut self, mode: PathNormalization) -> WebServerBuilder {
+        self.server.set_path_normalization(mode);
+        self
+    }
+
+    /// See `WebServer::set_rate_limit`.
+    pub fn rate_limit(mut self, limit: usize, window: Duration) -> WebServerBuilder {
+        self.server.set_rate_limit(limit, window);
+        self
+    }
+
+    /// See `WebServer::set_shutdown_grace_period`.
+    pub fn shutdown_grace_period(mut self, grace: Duration) -> WebServerBuilder {
+        self.server.set_shutdown_grace_period(grace);
+        self
+    }
+
+    /// See `WebServer::set_max_connections`.
+    pub fn max_connections(mut self, max: usize) -> WebServerBuilder {
+        self.server.set_max_connections(max);
+        self
+    }
+
+    /// See `WebServer::add_middleware`.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> WebServerBuilder {
+        self.server.add_middleware(middleware);
+        self
+    }
+
+    /// Finishes configuring the server.
+    pub fn build(self) -> WebServer {
+        self.server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_are_set() {
+        let mut routes = Routes::new();
+        routes.insert(String::from("/"), String::from("hello.html"));
+
+        let server = WebServer::builder()
+            .thread_limit(2)
+            .routes(routes)
+            .read_timeout(None)
+            .max_request_bytes(512)
+            .server_name("test/1.0")
+            .not_found_page("missing.html")
+            .trailing_slash(TrailingSlash::Merge)
+            .build();
+
+        assert_eq!(server.thread_limit, 2);
+        assert_eq!(server.config.read_timeout, None);
+        assert_eq!(server.config.max_request_bytes, 512);
+        assert_eq!(server.config.server_name, "test/1.0");
+        assert_eq!(
+            server.config.error_pages[&StatusCode::NOT_FOUND],
+            "missing.html"
+        );
+        assert_eq!(server.config.trailing_slash, TrailingSlash::Merge);
+
+        let routes = server.sites.default.routes.read().unwrap();
+        assert_eq!(routes.files["/"], "hello.html");
+    }
+}
@@ -0,0 +1,109 @@
+use super::socket::Socket;
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The connections a server is in the middle of handling.
+#[derive(Default)]
+pub(crate) struct Connections {
+    /// Cuts off each open stream, should it come to that.
+    open: Mutex<HashMap<usize, Box<dyn Fn() + Send>>>,
+    closed: Condvar,
+    next_id: AtomicUsize,
+}
+
+impl Connections {
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn track<S: Socket>(self: &Arc<Self>, stream: &S) -> Option<Tracked> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = stream.try_clone().ok()?;
+        let cut_off = move || {
+            let _ = stream.shutdown(Shutdown::Both);
+        };
+
+        self.open.lock().unwrap().insert(id, Box::new(cut_off));
+
+        Some(Tracked {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// How many connections are open.
+    pub(crate) fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// Waits up to `grace` for every open connection to finish, then cuts off
+    /// any that haven't.
+    ///
+    /// Returns how many had to be cut off.
+    pub(crate) fn drain(&self, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        let mut open = self.open.lock().unwrap();
+
+        while !open.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            if left == Duration::ZERO {
+                break;
+            }
+
+            open = self.closed.wait_timeout(open, left).unwrap().0;
+        }
+
+        // Whoever is handling these will see their reads and writes fail
+        for cut_off in open.values() {
+            cut_off();
+        }
+
+        open.len()
+    }
+}
+
+/// Marks a connection as finished when dropped.
+pub(crate) struct Tracked {
+    connections: Arc<Connections>,
+    id: usize,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    #[test]
+    fn draining_waits_then_cuts_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let connections = Arc::new(Connections::default());
+
+        let tracked = connections.track(&client).unwrap();
+        let finisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(tracked);
+        });
+
+        assert_eq!(connections.drain(Duration::from_secs(5)), 0);
+        finisher.join().unwrap();
+
+        let _stuck = connections.track(&client).unwrap();
+        assert_eq!(connections.drain(Duration::from_millis(50)), 1);
+    }
+}
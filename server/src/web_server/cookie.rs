@@ -0,0 +1,298 @@
+use http::{
+    header::{InvalidHeaderValue, COOKIE, SET_COOKIE},
+    HeaderValue, Request, Response,
+};
+use std::{collections::HashMap, error::Error, fmt, time::Duration};
+
+/// Parses the cookies a request carries into a map.
+///
+/// Values wrapped in double quotes have them removed. Pairs without a name
+/// or an `=` are skipped. Browsers send the most specific cookie first when
+/// names clash, so the first one wins.
+pub fn parse_cookies<B>(request: &Request<B>) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    let pairs = request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'));
+
+    for pair in pairs {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            continue;
+        }
+
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        cookies
+            .entry(String::from(name))
+            .or_insert_with(|| String::from(value));
+    }
+
+    cookies
+}
+
+/// Whether a cookie is sent along with requests from other sites.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie for a response to set, with its attributes.
+///
+/// Formatting one gives the value of its `Set-Cookie` header.
+#[derive(Clone, Debug)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// Fails if the name isn't a token, or the value has characters cookies
+    /// can't carry, like `;`, `,`, whitespace or control characters.
+    pub fn new(name: &str, value: &str) -> Result<SetCookie, InvalidCookie> {
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(InvalidCookie(String::from(name)));
+        }
+
+        let unquoted = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        if !unquoted.bytes().all(is_cookie_octet) {
+            return Err(InvalidCookie(String::from(value)));
+        }
+
+        Ok(SetCookie {
+            name: String::from(name),
+            value: String::from(value),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    /// Fails if the path has a `;` or control characters.
+    pub fn path(mut self, path: &str) -> Result<SetCookie, InvalidCookie> {
+        self.path = Some(attribute(path)?);
+        Ok(self)
+    }
+
+    /// Fails if the domain has a `;` or control characters.
+    pub fn domain(mut self, domain: &str) -> Result<SetCookie, InvalidCookie> {
+        self.domain = Some(attribute(domain)?);
+        Ok(self)
+    }
+
+    /// Sets how long the cookie lasts. Zero deletes it.
+    pub fn max_age(mut self, max_age: Duration) -> SetCookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> SetCookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Hides the cookie from scripts on the page.
+    pub fn http_only(mut self, http_only: bool) -> SetCookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Browsers ignore `SameSite=None` on cookies that aren't also secure, so
+    /// it makes the cookie secure too.
+    pub fn same_site(mut self, same_site: SameSite) -> SetCookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// Adds a `Set-Cookie` header for the cookie, keeping any already set.
+    pub fn add_to<B>(&self, response: &mut Response<B>) -> Result<(), InvalidHeaderValue> {
+        let value = HeaderValue::from_str(&self.to_string())?;
+        response.headers_mut().append(SET_COOKIE, value);
+
+        Ok(())
+    }
+}
+
+/// Whether `byte` may appear in a cookie's name.
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&byte)
+}
+
+/// Whether `byte` may appear in a cookie's value.
+fn is_cookie_octet(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"\",;\\".contains(&byte)
+}
+
+/// Checks an attribute's value can't end it early or break the header.
+fn attribute(value: &str) -> Result<String, InvalidCookie> {
+    if value
+        .bytes()
+        .any(|byte| byte == b';' || byte.is_ascii_control())
+    {
+        return Err(InvalidCookie(String::from(value)));
+    }
+
+    Ok(String::from(value))
+}
+
+/// A cookie name, value or attribute that can't go in a `Set-Cookie` header.
+#[derive(Debug)]
+pub struct InvalidCookie(pub String);
+
+impl fmt::Display for InvalidCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cookie: {}", self.0)
+    }
+}
+
+impl Error for InvalidCookie {}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+
+        if self.secure || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_are_parsed() {
+        let request = Request::get("/")
+            .header(COOKIE, "session=abc123; theme=\"dark mode\" ;empty=")
+            .header(COOKIE, "session=older; lang=en")
+            .body(())
+            .unwrap();
+
+        let cookies = parse_cookies(&request);
+
+        assert_eq!(cookies.len(), 4);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark mode");
+        assert_eq!(cookies["empty"], "");
+        assert_eq!(cookies["lang"], "en");
+    }
+
+    #[test]
+    fn malformed_pairs_are_skipped() {
+        let request = Request::get("/")
+            .header(COOKIE, "novalue; =nameless; bad name=1; ok=1")
+            .body(())
+            .unwrap();
+
+        let cookies = parse_cookies(&request);
+
+        assert_eq!(cookies.len(), 1);
+        assert_eq!(cookies["ok"], "1");
+    }
+
+    #[test]
+    fn set_cookie_headers() {
+        let session = SetCookie::new("session", "abc123")
+            .unwrap()
+            .path("/")
+            .unwrap()
+            .domain("example.com")
+            .unwrap()
+            .max_age(Duration::from_secs(3600))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        let theme = SetCookie::new("theme", "dark")
+            .unwrap()
+            .same_site(SameSite::None);
+
+        let mut response = Response::new(());
+        session.add_to(&mut response).unwrap();
+        theme.add_to(&mut response).unwrap();
+
+        let cookies: Vec<_> = response.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(
+            cookies,
+            [
+                "session=abc123; Path=/; Domain=example.com; Max-Age=3600; Secure; HttpOnly; SameSite=Lax",
+                "theme=dark; Secure; SameSite=None",
+            ]
+        );
+    }
+
+    #[test]
+    fn cookies_that_would_inject_attributes_are_rejected() {
+        assert!(SetCookie::new("id", "\"quoted\"").is_ok());
+
+        for (name, value) in &[
+            ("id", "1; Domain=evil.test"),
+            ("id", "a,b"),
+            ("id", "a b"),
+            ("id", "a\nSet-Cookie: x=1"),
+            ("bad name", "1"),
+            ("id;", "1"),
+            ("", "1"),
+        ] {
+            assert!(
+                SetCookie::new(name, value).is_err(),
+                "{:?}={:?}",
+                name,
+                value
+            );
+        }
+
+        let cookie = SetCookie::new("id", "1").unwrap();
+        assert!(cookie.clone().path("/; HttpOnly").is_err());
+        assert!(cookie.domain("a.test\r\n").is_err());
+    }
+}
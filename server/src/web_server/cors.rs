@@ -0,0 +1,238 @@
+use http::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    Method, Request, Response,
+};
+use std::time::Duration;
+
+use super::middleware::{Middleware, Next};
+
+/// Lets pages from other origins make requests, following the CORS rules.
+///
+/// Requests from origins that aren't allowed are passed on untouched, and
+/// browsers will keep the response from them. Preflight requests are
+/// answered here without reaching the router.
+#[derive(Clone, Debug, Default)]
+pub struct Cors {
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Creates a policy that allows no origins until told otherwise.
+    pub fn new() -> Cors {
+        Cors::default()
+    }
+
+    /// Allows requests from `origin`, like `https://example.com`, or from
+    /// anywhere with `*`.
+    pub fn allow_origin(mut self, origin: &str) -> Cors {
+        if origin == "*" {
+            self.any_origin = true;
+        } else {
+            self.origins.push(String::from(origin));
+        }
+
+        self
+    }
+
+    /// Sets the methods preflight requests may ask to use.
+    pub fn allow_methods(mut self, methods: &[&str]) -> Cors {
+        self.methods = methods.iter().map(|&method| String::from(method)).collect();
+        self
+    }
+
+    /// Sets the request headers preflight requests may ask to send.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = headers.iter().map(|&header| String::from(header)).collect();
+        self
+    }
+
+    /// Lets requests carry cookies and other credentials.
+    ///
+    /// Browsers refuse a wildcard origin on credentialed requests, so the
+    /// request's own origin is echoed back instead.
+    pub fn allow_credentials(mut self, allow: bool) -> Cors {
+        self.credentials = allow;
+        self
+    }
+
+    /// Sets how long browsers may cache the answer to a preflight.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` to send to `origin`, if it's allowed.
+    fn allowed_origin(&self, origin: &str) -> Option<HeaderValue> {
+        if self.any_origin && !self.credentials {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        if self.any_origin || self.origins.iter().any(|allowed| allowed == origin) {
+            return HeaderValue::from_str(origin).ok();
+        }
+
+        None
+    }
+
+    /// Adds the headers every response to an allowed origin gets.
+    fn add_headers(&self, response: &mut Response<Vec<u8>>, allowed: HeaderValue) {
+        let headers = response.headers_mut();
+
+        // The answer depends on the origin unless it's the same for everyone
+        if allowed != "*" {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    /// Answers a preflight request.
+    fn preflight(&self, allowed: HeaderValue) -> Response<Vec<u8>> {
+        let mut response = Response::new(Vec::new());
+        *response.status_mut() = http::StatusCode::NO_CONTENT;
+
+        self.add_headers(&mut response, allowed);
+
+        let headers = response.headers_mut();
+
+        if let Ok(methods) = HeaderValue::from_str(&self.methods.join(", ")) {
+            if !methods.is_empty() {
+                headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+            }
+        }
+
+        if let Ok(allowed) = HeaderValue::from_str(&self.headers.join(", ")) {
+            if !allowed.is_empty() {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+        let allowed = request
+            .headers()
+            .get(ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+            .and_then(|origin| self.allowed_origin(origin));
+
+        let allowed = match allowed {
+            Some(allowed) => allowed,
+            None => return next.run(request),
+        };
+
+        let is_preflight = request.method() == Method::OPTIONS
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+        if is_preflight {
+            return self.preflight(allowed);
+        }
+
+        let mut response = next.run(request);
+        self.add_headers(&mut response, allowed);
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn run(cors: Cors, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let endpoint = |_: Request<Vec<u8>>| Response::new(b"routed".to_vec());
+        let middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(cors)];
+
+        Next::new(&middleware, &endpoint).run(request)
+    }
+
+    #[test]
+    fn preflights_are_answered() {
+        let cors = Cors::new()
+            .allow_origin("https://a.test")
+            .allow_methods(&["GET", "PUT"])
+            .allow_headers(&["Content-Type"])
+            .max_age(Duration::from_secs(600));
+
+        let request = Request::options("/api")
+            .header(ORIGIN, "https://a.test")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Vec::new())
+            .unwrap();
+        let response = run(cors, request);
+
+        let headers = response.headers();
+        assert_eq!(response.status(), 204);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://a.test");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "Content-Type");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[VARY], "origin");
+    }
+
+    #[test]
+    fn unset_lists_are_left_out_of_preflights() {
+        let request = Request::options("/api")
+            .header(ORIGIN, "https://a.test")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Vec::new())
+            .unwrap();
+        let response = run(Cors::new().allow_origin("https://a.test"), request);
+
+        let headers = response.headers();
+        assert_eq!(response.status(), 204);
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_HEADERS));
+    }
+
+    #[test]
+    fn simple_requests_get_allow_origin() {
+        let request = |origin: &str| {
+            Request::get("/api")
+                .header(ORIGIN, origin)
+                .body(Vec::new())
+                .unwrap()
+        };
+
+        let response = run(Cors::new().allow_origin("*"), request("https://b.test"));
+        assert_eq!(response.body(), b"routed");
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        // Credentials rule out the wildcard
+        let cors = Cors::new().allow_origin("*").allow_credentials(true);
+        let response = run(cors, request("https://b.test"));
+        let headers = response.headers();
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://b.test");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+        let cors = Cors::new().allow_origin("https://a.test");
+        let response = run(cors, request("https://b.test"));
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,191 @@
+use cacher::Cacher;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A file's contents as of its last modification.
+#[derive(Clone)]
+pub(crate) struct CachedFile {
+    pub(crate) modified: SystemTime,
+    pub(crate) contents: Arc<Vec<u8>>,
+    /// A quoted tag that changes whenever the contents do.
+    pub(crate) etag: String,
+}
+
+type Load = fn(PathBuf) -> Option<CachedFile>;
+
+/// How many bytes of file contents are kept in memory at most.
+const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Keeps the contents of served files in memory.
+///
+/// A file is reread whenever its modification time changes. Once the files
+/// held add up to too many bytes, the least recently served go first.
+pub(crate) struct FileCache {
+    files: Mutex<Cacher<Load, PathBuf, Option<CachedFile>>>,
+}
+
+impl FileCache {
+    pub(crate) fn new() -> FileCache {
+        FileCache::with_max_bytes(MAX_CACHED_BYTES)
+    }
+
+    fn with_max_bytes(max_bytes: usize) -> FileCache {
+        let weigh = |file: &Option<CachedFile>| file.as_ref().map_or(0, |file| file.contents.len());
+
+        FileCache {
+            files: Mutex::new(Cacher::with_weigher(load as Load, max_bytes, weigh)),
+        }
+    }
+
+    /// Reads a file, from memory if it hasn't changed since last time.
+    ///
+    /// The file is read from disk without holding the lock, so a slow read
+    /// doesn't hold up requests for other files.
+    pub(crate) fn read(&self, path: &Path) -> io::Result<CachedFile> {
+        let modified = fs::metadata(path)?.modified()?;
+        let path = path.to_path_buf();
+
+        {
+            let mut files = self.files.lock().unwrap();
+
+            let fresh = matches!(files.get(&path), Some(Some(file)) if file.modified == modified);
+            if fresh {
+                if let Some(file) = files.value(path.clone()) {
+                    return Ok(file.clone());
+                }
+            }
+        }
+
+        // Failures aren't remembered, the file may turn up later
+        let file = load(path.clone()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("could not read {}", path.display()),
+            )
+        })?;
+
+        self.store(path, file.clone());
+
+        Ok(file)
+    }
+
+    /// Caches a file that's been read, unless another request has already
+    /// cached a version at least as fresh.
+    fn store(&self, path: PathBuf, file: CachedFile) {
+        let mut files = self.files.lock().unwrap();
+
+        let older = match files.get(&path) {
+            Some(Some(cached)) => cached.modified < file.modified,
+            Some(None) => true,
+            None => false,
+        };
+
+        if older {
+            files.remove(&path);
+        }
+
+        files.compute_if_absent(path, |_| Some(file));
+    }
+}
+
+fn load(path: PathBuf) -> Option<CachedFile> {
+    let modified = fs::metadata(&path).ok()?.modified().ok()?;
+    let contents = fs::read(&path).ok()?;
+
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    Some(CachedFile {
+        modified,
+        contents: Arc::new(contents),
+        etag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn changed_files_are_reread() {
+        let path = std::env::temp_dir().join(format!("file-cache-test-{}", std::process::id()));
+        fs::write(&path, "first").unwrap();
+
+        let cache = FileCache::new();
+        let first = cache.read(&path).unwrap();
+        assert_eq!(*first.contents, b"first");
+
+        // Make sure the modification time actually moves
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        fs::write(&path, "second").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let second = cache.read(&path).unwrap();
+        assert_eq!(*second.contents, b"second");
+        assert_ne!(first.etag, second.etag);
+
+        fs::remove_file(&path).unwrap();
+        assert!(cache.read(&path).is_err());
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let dir = std::env::temp_dir().join(format!("file-cache-bound-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(dir.join(name), [0; 10]).unwrap();
+        }
+
+        let cache = FileCache::with_max_bytes(25);
+        for name in &["a", "b", "c"] {
+            cache.read(&dir.join(name)).unwrap();
+        }
+
+        let files = cache.files.lock().unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(!files.contains_key(&dir.join("a")));
+        drop(files);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fresher_entries_are_kept() {
+        let path = std::env::temp_dir().join(format!("file-cache-fresh-{}", std::process::id()));
+        fs::write(&path, "newer").unwrap();
+
+        let cache = FileCache::new();
+        let newer = cache.read(&path).unwrap();
+        let version = |modified, etag: &str| CachedFile {
+            modified,
+            contents: Arc::new(Vec::new()),
+            etag: String::from(etag),
+        };
+
+        // A slower request that read an older version loses out
+        let before = newer.modified - Duration::from_secs(10);
+        cache.store(path.clone(), version(before, "\"older\""));
+        assert_eq!(cache.read(&path).unwrap().etag, newer.etag);
+
+        let after = newer.modified + Duration::from_secs(10);
+        cache.store(path.clone(), version(after, "\"later\""));
+        let files = cache.files.lock().unwrap();
+        assert_eq!(
+            files.get(&path).unwrap().as_ref().unwrap().etag,
+            "\"later\""
+        );
+        drop(files);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
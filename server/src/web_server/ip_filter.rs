@@ -0,0 +1,140 @@
+use std::{error::Error, fmt, net::IpAddr};
+
+/// A range of addresses written in CIDR notation, like `10.0.0.0/8`.
+#[derive(Clone, Debug)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    /// Parses a range, or a single address standing for a range of one.
+    fn parse(range: &str) -> Result<IpRange, InvalidIpRange> {
+        let invalid = || InvalidIpRange(String::from(range));
+
+        let (network, prefix) = match range.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (range, None),
+        };
+
+        let network: IpAddr = network.trim().parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        Ok(IpRange { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Decides which client addresses may connect.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IpFilter {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub(crate) fn allow(&mut self, range: &str) -> Result<(), InvalidIpRange> {
+        self.allow.push(IpRange::parse(range)?);
+
+        Ok(())
+    }
+
+    pub(crate) fn deny(&mut self, range: &str) -> Result<(), InvalidIpRange> {
+        self.deny.push(IpRange::parse(range)?);
+
+        Ok(())
+    }
+
+    /// Whether `ip` may connect.
+    ///
+    /// Denied ranges always win. Once anything has been allowed, only allowed
+    /// ranges get in.
+    pub(crate) fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// An IP range that couldn't be parsed.
+#[derive(Debug)]
+pub struct InvalidIpRange(pub String);
+
+impl fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP range: {}", self.0)
+    }
+}
+
+impl Error for InvalidIpRange {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn denied_ranges_are_refused() {
+        let mut filter = IpFilter::default();
+        filter.deny("10.0.0.0/8").unwrap();
+        filter.deny("2001:db8::/32").unwrap();
+
+        assert!(!filter.permits(ip("10.1.2.3")));
+        assert!(!filter.permits(ip("::ffff:10.1.2.3")));
+        assert!(!filter.permits(ip("2001:db8::1")));
+        assert!(filter.permits(ip("11.0.0.1")));
+        assert!(filter.permits(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn allowlists_refuse_everything_else() {
+        let mut filter = IpFilter::default();
+        filter.allow("192.168.1.0/24").unwrap();
+        filter.allow("::1").unwrap();
+        filter.deny("192.168.1.13").unwrap();
+
+        assert!(filter.permits(ip("192.168.1.200")));
+        assert!(filter.permits(ip("::1")));
+        assert!(!filter.permits(ip("192.168.1.13")));
+        assert!(!filter.permits(ip("192.168.2.1")));
+        assert!(!filter.permits(ip("::2")));
+    }
+
+    #[test]
+    fn malformed_ranges_are_errors() {
+        let mut filter = IpFilter::default();
+
+        assert!(filter.allow("10.0.0.0/33").is_err());
+        assert!(filter.deny("localhost").is_err());
+        assert!(filter.allow("0.0.0.0/0").is_ok());
+    }
+}
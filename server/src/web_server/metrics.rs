@@ -0,0 +1,130 @@
+use http::StatusCode;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
+};
+
+use crate::thread_pool::ThreadPool;
+
+/// Counters describing what a server has been up to.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    requests: AtomicU64,
+    /// Responses sent, by the hundreds digit of their status.
+    responses: [AtomicU64; 5],
+    active_connections: AtomicU64,
+    /// The pool of a running server, so its load can be reported too.
+    pool: RwLock<Weak<ThreadPool>>,
+}
+
+impl Metrics {
+    pub(crate) fn request_received(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_sent(&self, status: StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as active until the guard is dropped.
+    pub(crate) fn connection_opened(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+
+        ConnectionGuard(self)
+    }
+
+    /// Reports on `pool` along with the other counters while it's running.
+    pub(crate) fn watch_pool(&self, pool: &Arc<ThreadPool>) {
+        *self.pool.write().unwrap() = Arc::downgrade(pool);
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut text = String::new();
+
+        let _ = writeln!(text, "# TYPE http_requests_total counter");
+        let _ = writeln!(
+            text,
+            "http_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(text, "# TYPE http_responses_total counter");
+        for (i, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "http_responses_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(text, "# TYPE http_active_connections gauge");
+        let _ = writeln!(
+            text,
+            "http_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        let pool = self.pool.read().unwrap().upgrade();
+        if let Some(pool) = pool {
+            let gauges = [
+                ("thread_pool_active_jobs", pool.active_count()),
+                ("thread_pool_queued_jobs", pool.queued_count()),
+                ("thread_pool_idle_workers", pool.idle_count()),
+            ];
+
+            for (name, value) in &gauges {
+                let _ = writeln!(text, "# TYPE {} gauge", name);
+                let _ = writeln!(text, "{} {}", name, value);
+            }
+        }
+
+        text
+    }
+}
+
+/// Marks a connection as no longer active when dropped.
+pub(crate) struct ConnectionGuard<'a>(&'a Metrics);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_are_rendered() {
+        let metrics = Metrics::default();
+        let guard = metrics.connection_opened();
+
+        metrics.request_received();
+        metrics.request_received();
+        metrics.response_sent(StatusCode::OK);
+        metrics.response_sent(StatusCode::NOT_FOUND);
+
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total 2\n"));
+        assert!(text.contains("http_responses_total{class=\"2xx\"} 1\n"));
+        assert!(text.contains("http_responses_total{class=\"4xx\"} 1\n"));
+        assert!(text.contains("http_responses_total{class=\"5xx\"} 0\n"));
+        assert!(text.contains("http_active_connections 1\n"));
+
+        drop(guard);
+        assert!(metrics.render().contains("http_active_connections 0\n"));
+        assert!(!metrics.render().contains("thread_pool"));
+
+        let pool = Arc::new(ThreadPool::new(2).unwrap());
+        metrics.watch_pool(&pool);
+        assert!(metrics.render().contains("thread_pool_idle_workers 2\n"));
+    }
+}
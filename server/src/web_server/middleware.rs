@@ -0,0 +1,104 @@
+use http::{Request, Response};
+use std::{sync::Arc, time::Instant};
+
+/// Code that runs around every request.
+///
+/// Middleware can inspect or change the request before passing it on with
+/// `next.run(request)`, and inspect or change the response that comes back.
+/// Returning a response without calling `next` short-circuits the request so
+/// that nothing further down the chain sees it.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>>;
+}
+
+/// The rest of the middleware chain, ending with the router.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(Request<Vec<u8>>) -> Response<Vec<u8>>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middleware: &'a [Arc<dyn Middleware>],
+        endpoint: &'a dyn Fn(Request<Vec<u8>>) -> Response<Vec<u8>>,
+    ) -> Next<'a> {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// Passes the request on down the chain.
+    pub fn run(self, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// Logs a line for every request with its outcome and how long it took.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn handle(&self, request: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        let start = Instant::now();
+
+        let response = next.run(request);
+
+        log::info!(
+            "{} {} -> {} in {:?}",
+            method,
+            uri,
+            response.status().as_u16(),
+            start.elapsed()
+        );
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Turns away any request without an `Authorization` header.
+    struct RequireAuth;
+
+    impl Middleware for RequireAuth {
+        fn handle(&self, request: Request<Vec<u8>>, next: Next<'_>) -> Response<Vec<u8>> {
+            if request.headers().contains_key("Authorization") {
+                next.run(request)
+            } else {
+                Response::builder()
+                    .status(401)
+                    .body(b"Unauthorized".to_vec())
+                    .unwrap()
+            }
+        }
+    }
+
+    fn run(middleware: &[Arc<dyn Middleware>], request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+        let endpoint = |_: Request<Vec<u8>>| Response::new(b"routed".to_vec());
+
+        Next::new(middleware, &endpoint).run(request)
+    }
+
+    #[test]
+    fn middleware_can_short_circuit() {
+        let middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(Logger), Arc::new(RequireAuth)];
+
+        let request = Request::get("/").body(Vec::new()).unwrap();
+        assert_eq!(run(&middleware, request).status(), 401);
+
+        let request = Request::get("/")
+            .header("Authorization", "Bearer token")
+            .body(Vec::new())
+            .unwrap();
+        let response = run(&middleware, request);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"routed");
+    }
+}
@@ -0,0 +1,236 @@
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderMap, Request, Response,
+};
+use std::{error::Error, fmt};
+
+/// One part of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct MultipartPart {
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl MultipartPart {
+    /// The name of the form field this part holds.
+    pub fn name(&self) -> Option<&str> {
+        self.disposition_param("name")
+    }
+
+    /// The name of the uploaded file, if this part is a file.
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition_param("filename")
+    }
+
+    /// Finds a parameter of the `Content-Disposition` header.
+    fn disposition_param(&self, key: &str) -> Option<&str> {
+        let disposition = self.headers.get(CONTENT_DISPOSITION)?.to_str().ok()?;
+
+        disposition.split(';').skip(1).find_map(|param| {
+            let (name, value) = param.trim().split_once('=')?;
+
+            if name.trim().eq_ignore_ascii_case(key) {
+                Some(value.trim().trim_matches('"'))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Splits a `multipart/form-data` body into its parts.
+///
+/// Bad uploads are the client's fault, so errors turn into a 400 with
+/// `Response::from`.
+pub fn parse_multipart(request: &Request<Vec<u8>>) -> Result<Vec<MultipartPart>, MultipartError> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(MultipartError::NotMultipart)?;
+
+    let mut params = content_type.split(';');
+    let mime = params.next().unwrap_or("").trim();
+
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(MultipartError::NotMultipart);
+    }
+
+    let boundary = params
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+        .ok_or(MultipartError::MissingBoundary)?;
+
+    parse_parts(request.body(), boundary.as_bytes())
+}
+
+/// Splits a body on a boundary.
+fn parse_parts(body: &[u8], boundary: &[u8]) -> Result<Vec<MultipartPart>, MultipartError> {
+    let delimiter = [b"--", boundary].concat();
+    let mut parts = Vec::new();
+
+    // Anything before the first delimiter is a preamble to be ignored
+    let start = find(body, &delimiter).ok_or(MultipartError::Malformed)?;
+    let mut rest = &body[start + delimiter.len()..];
+
+    loop {
+        // The last delimiter is followed by two dashes
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or(MultipartError::Malformed)?;
+
+        // A part may have no headers at all, leaving just the blank line
+        let headers = if let Some(body) = rest.strip_prefix(b"\r\n") {
+            rest = body;
+            HeaderMap::new()
+        } else {
+            let head_end = find(rest, b"\r\n\r\n").ok_or(MultipartError::Malformed)?;
+            let headers = parse_headers(&rest[..head_end])?;
+            rest = &rest[head_end + 4..];
+            headers
+        };
+
+        let close = [b"\r\n", delimiter.as_slice()].concat();
+        let body_end = find(rest, &close).ok_or(MultipartError::Malformed)?;
+
+        parts.push(MultipartPart {
+            headers,
+            body: rest[..body_end].to_vec(),
+        });
+
+        rest = &rest[body_end + close.len()..];
+    }
+}
+
+/// Parses the headers at the top of a part.
+fn parse_headers(head: &[u8]) -> Result<HeaderMap, MultipartError> {
+    let mut headers = HeaderMap::new();
+
+    for line in head.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let colon = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or(MultipartError::Malformed)?;
+
+        let name = HeaderName::from_bytes(&line[..colon]).map_err(|_| MultipartError::Malformed)?;
+        let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+            .map_err(|_| MultipartError::Malformed)?;
+
+        headers.append(name, value);
+    }
+
+    Ok(headers)
+}
+
+/// Finds where `needle` first shows up in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Why a multipart body couldn't be parsed.
+#[derive(Debug)]
+pub enum MultipartError {
+    NotMultipart,
+    MissingBoundary,
+    Malformed,
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "body is not multipart/form-data"),
+            MultipartError::MissingBoundary => write!(f, "missing or invalid boundary"),
+            MultipartError::Malformed => write!(f, "malformed multipart body"),
+        }
+    }
+}
+
+impl Error for MultipartError {}
+
+impl From<MultipartError> for Response<String> {
+    fn from(err: MultipartError) -> Response<String> {
+        let mut response = Response::new(format!("Bad request: {}", err));
+        *response.status_mut() = http::StatusCode::BAD_REQUEST;
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(content_type: &str, body: &[u8]) -> Request<Vec<u8>> {
+        Request::post("/upload")
+            .header(CONTENT_TYPE, content_type)
+            .body(body.to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn text_and_file_parts() {
+        let body = b"preamble\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\
+            \r\n\
+            \x89PNG\r\n\x00\xff\r\n\
+            --XyZ--\r\n";
+        let request = upload("multipart/form-data; boundary=\"XyZ\"", body);
+
+        let parts = parse_multipart(&request).unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name(), Some("title"));
+        assert_eq!(parts[0].filename(), None);
+        assert_eq!(parts[0].body, b"holiday");
+        assert_eq!(parts[1].name(), Some("photo"));
+        assert_eq!(parts[1].filename(), Some("a.png"));
+        assert_eq!(parts[1].headers[CONTENT_TYPE], "image/png");
+        assert_eq!(parts[1].body, b"\x89PNG\r\n\x00\xff");
+    }
+
+    #[test]
+    fn parts_without_headers() {
+        let body = b"--b\r\n\r\nfirst\r\n--b\r\n\r\n\r\n--b--";
+        let request = upload("multipart/form-data; boundary=b", body);
+
+        let parts = parse_multipart(&request).unwrap();
+
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].headers.is_empty());
+        assert_eq!(parts[0].name(), None);
+        assert_eq!(parts[0].body, b"first");
+        assert!(parts[1].headers.is_empty());
+        assert_eq!(parts[1].body, b"");
+    }
+
+    #[test]
+    fn malformed_bodies_are_bad_requests() {
+        let missing_boundary = upload("multipart/form-data", b"");
+        let unterminated = upload("multipart/form-data; boundary=b", b"--b\r\n\r\nno end");
+
+        for request in &[missing_boundary, unterminated] {
+            let response = Response::from(parse_multipart(request).unwrap_err());
+            assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/// What a `Range` header asks for out of a body of a given length.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ByteRange {
+    /// The whole body, because the header can't be used.
+    Whole,
+    /// The bytes from `start` to `end`, inclusive.
+    Partial { start: u64, end: u64 },
+    /// A range that lies entirely past the end of the body.
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header like `bytes=0-499`, `bytes=500-`, or `bytes=-500`.
+///
+/// Only single ranges are supported. Anything else, including headers that
+/// can't be parsed, is answered with the whole body, which the spec allows.
+pub(crate) fn parse_range(value: &str, len: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+
+    // A missing start asks for the last so many bytes
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            },
+            Err(_) => ByteRange::Whole,
+        };
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Whole,
+    };
+
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Whole,
+        }
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial {
+        start,
+        end: end.min(len - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-4", 10),
+            ByteRange::Partial { start: 0, end: 4 }
+        );
+        assert_eq!(
+            parse_range("bytes=5-", 10),
+            ByteRange::Partial { start: 5, end: 9 }
+        );
+        assert_eq!(
+            parse_range("bytes=8-100", 10),
+            ByteRange::Partial { start: 8, end: 9 }
+        );
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=-3", 10),
+            ByteRange::Partial { start: 7, end: 9 }
+        );
+        assert_eq!(
+            parse_range("bytes=-30", 10),
+            ByteRange::Partial { start: 0, end: 9 }
+        );
+        assert_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn unusable_ranges() {
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-2", 10), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Whole);
+        assert_eq!(parse_range("lines=1-2", 10), ByteRange::Whole);
+        assert_eq!(parse_range("bytes=a-b", 10), ByteRange::Whole);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Limits how many requests each client may make in a sliding window.
+pub(crate) struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// When each client's recent requests came in, oldest first.
+    clients: HashMap<IpAddr, VecDeque<Instant>>,
+    last_pruned: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: usize, window: Duration) -> RateLimiter {
+        RateLimiter {
+            limit,
+            window,
+            state: Mutex::new(State {
+                clients: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request from `ip` made at `now`.
+    ///
+    /// Requests over the limit aren't counted, and get back how long until
+    /// the client may try again.
+    pub(crate) fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let window = self.window;
+
+        // Forget clients that have gone quiet, so the map doesn't keep growing
+        if now.saturating_duration_since(state.last_pruned) >= window {
+            state.clients.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|&last| now.saturating_duration_since(last) < window)
+            });
+            state.last_pruned = now;
+        }
+
+        let times = state.clients.entry(ip).or_default();
+
+        while times
+            .front()
+            .is_some_and(|&first| now.saturating_duration_since(first) >= window)
+        {
+            times.pop_front();
+        }
+
+        if times.len() >= self.limit {
+            let oldest = times.front().copied().unwrap_or(now);
+
+            return Err(window.saturating_sub(now.saturating_duration_since(oldest)));
+        }
+
+        times.push_back(now);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_over_the_limit_wait_for_the_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let other = IpAddr::from([10, 0, 0, 2]);
+        let start = Instant::now();
+
+        assert!(limiter.check(client, start).is_ok());
+        assert!(limiter
+            .check(client, start + Duration::from_secs(4))
+            .is_ok());
+        assert_eq!(
+            limiter.check(client, start + Duration::from_secs(6)),
+            Err(Duration::from_secs(4))
+        );
+        assert!(limiter.check(other, start + Duration::from_secs(6)).is_ok());
+
+        // The first request has slid out of the window
+        assert!(limiter
+            .check(client, start + Duration::from_secs(10))
+            .is_ok());
+    }
+
+    #[test]
+    fn quiet_clients_are_pruned() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(1));
+        let start = Instant::now();
+
+        for i in 0..100u8 {
+            limiter.check(IpAddr::from([10, 0, 0, i]), start).unwrap();
+        }
+
+        let later = start + Duration::from_secs(5);
+        limiter.check(IpAddr::from([10, 0, 1, 0]), later).unwrap();
+
+        assert_eq!(limiter.state.lock().unwrap().clients.len(), 1);
+    }
+}
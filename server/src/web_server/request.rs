@@ -0,0 +1,141 @@
+use http::{header::CONTENT_TYPE, Request};
+use std::collections::HashMap;
+
+/// Parses the query string of a request into a map.
+///
+/// Keys and values are percent-decoded, with `+` standing in for a space.
+/// Keys without a value map to an empty string, and when a key is repeated
+/// the last value wins.
+pub fn query_params<B>(request: &Request<B>) -> HashMap<String, String> {
+    match request.uri().query() {
+        Some(query) => parse_pairs(query),
+        None => HashMap::new(),
+    }
+}
+
+/// Parses a form submitted in the body of a request into a map.
+///
+/// Only bodies sent as `application/x-www-form-urlencoded` are parsed, and
+/// anything else gives an empty map. Pairs are decoded the same way as by
+/// `query_params`.
+pub fn parse_form(request: &Request<Vec<u8>>) -> HashMap<String, String> {
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| {
+            mime.trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        });
+
+    if !is_form {
+        return HashMap::new();
+    }
+
+    parse_pairs(&String::from_utf8_lossy(request.body()))
+}
+
+/// Parses `a=1&b=2` style pairs into a map.
+fn parse_pairs(input: &str) -> HashMap<String, String> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+/// Decodes a single query component, keeping malformed escapes as they are.
+fn decode_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+
+    percent_decode(&component).unwrap_or(component)
+}
+
+/// Decodes `%XX` escapes in a string.
+///
+/// Returns `None` if an escape is malformed or the result isn't UTF-8.
+pub(crate) fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_parameters() {
+        let request = Request::get("/search?q=hello%20world&empty=&flag&n=1&n=2&sp=a+b")
+            .body(())
+            .unwrap();
+
+        let params = query_params(&request);
+
+        assert_eq!(params["q"], "hello world");
+        assert_eq!(params["empty"], "");
+        assert_eq!(params["flag"], "");
+        assert_eq!(params["n"], "2");
+        assert_eq!(params["sp"], "a b");
+    }
+
+    #[test]
+    fn no_query_is_empty() {
+        let request = Request::get("/search").body(()).unwrap();
+
+        assert!(query_params(&request).is_empty());
+    }
+
+    #[test]
+    fn form_bodies() {
+        let request = Request::post("/signup")
+            .header(
+                CONTENT_TYPE,
+                "application/x-www-form-urlencoded; charset=utf-8",
+            )
+            .body(b"a=1&b=hello%20world&empty=&name=J%C3%BCrgen+M".to_vec())
+            .unwrap();
+
+        let form = parse_form(&request);
+
+        assert_eq!(form["a"], "1");
+        assert_eq!(form["b"], "hello world");
+        assert_eq!(form["empty"], "");
+        assert_eq!(form["name"], "Jürgen M");
+    }
+
+    #[test]
+    fn other_bodies_are_not_forms() {
+        let request = Request::post("/signup")
+            .header(CONTENT_TYPE, "text/plain")
+            .body(b"a=1".to_vec())
+            .unwrap();
+
+        assert!(parse_form(&request).is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Response, StatusCode,
+};
+use serde::Serialize;
+
+/// Builds a response with `value` serialized as JSON.
+///
+/// If the value can't be serialized, or `status` isn't a valid status code,
+/// a 500 is sent instead.
+pub fn json_response<T: Serialize + ?Sized>(status: u16, value: &T) -> Response<String> {
+    let body = serde_json::to_string(value).map_err(|err| err.to_string());
+
+    let response = body.and_then(|body| {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, body.len())
+            .body(body)
+            .map_err(|err| err.to_string())
+    });
+
+    response.unwrap_or_else(|err| {
+        log::error!("could not build JSON response: {}", err);
+
+        let mut response = Response::new(String::from("Internal server error"));
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+        response
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn values_are_serialized() {
+        let mut value = BTreeMap::new();
+        value.insert("name", "ferris");
+        value.insert("kind", "crab");
+
+        let response = json_response(201, &value);
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "31");
+        assert_eq!(response.body(), r#"{"kind":"crab","name":"ferris"}"#);
+    }
+
+    #[test]
+    fn unserializable_values_are_errors() {
+        // JSON object keys have to be strings
+        let mut value = HashMap::new();
+        value.insert(vec![1], 2);
+
+        let response = json_response(200, &value);
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
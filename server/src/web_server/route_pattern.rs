@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+/// Values captured from the path by a route pattern.
+///
+/// Handlers registered with a pattern find these in the request's extensions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathParams(HashMap<String, String>);
+
+impl PathParams {
+    /// Gets a captured value by name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+impl From<HashMap<String, String>> for PathParams {
+    fn from(params: HashMap<String, String>) -> PathParams {
+        PathParams(params)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name` matches any one segment.
+    Param(String),
+    /// `*name` matches everything left, and can only come last.
+    Wildcard(String),
+}
+
+/// A route like `/users/:id` or `/files/*path`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RoutePattern {
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    /// Parses a route, if it has any named segments or a wildcard.
+    ///
+    /// Plain paths give `None` since they don't need a pattern.
+    pub(crate) fn parse(route: &str) -> Option<RoutePattern> {
+        let segments: Vec<Segment> = split(route)
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(String::from(name))
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(String::from(name))
+                } else {
+                    Segment::Literal(String::from(segment))
+                }
+            })
+            .collect();
+
+        let dynamic = segments
+            .iter()
+            .any(|segment| !matches!(segment, Segment::Literal(_)));
+
+        // Anything after a wildcard could never be reached, so treat it literally
+        let wildcard_last = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)))
+            .is_none_or(|position| position == segments.len() - 1);
+
+        if dynamic && wildcard_last {
+            Some(RoutePattern { segments })
+        } else {
+            None
+        }
+    }
+
+    /// Matches a path, capturing the values of any named segments.
+    pub(crate) fn matches(&self, path: &str) -> Option<PathParams> {
+        let mut params = HashMap::new();
+        let mut parts = split(path);
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), String::from(parts.next()?));
+                }
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.insert(name.clone(), rest.join("/"));
+                }
+            }
+        }
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(PathParams(params)),
+        }
+    }
+
+    /// How specific the pattern is, so the best of several matches wins.
+    ///
+    /// More literal segments beat fewer, then more named segments beat
+    /// fewer, and finally a pattern without a wildcard beats one with.
+    pub(crate) fn specificity(&self) -> (usize, usize, bool) {
+        let count =
+            |wanted: fn(&Segment) -> bool| self.segments.iter().filter(|s| wanted(s)).count();
+
+        (
+            count(|segment| matches!(segment, Segment::Literal(_))),
+            count(|segment| matches!(segment, Segment::Param(_))),
+            count(|segment| matches!(segment, Segment::Wildcard(_))) == 0,
+        )
+    }
+}
+
+/// Splits a path into its segments, ignoring the leading slash.
+fn split(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_paths_are_not_patterns() {
+        assert_eq!(RoutePattern::parse("/users/new"), None);
+    }
+
+    #[test]
+    fn named_segments_are_captured() {
+        let pattern = RoutePattern::parse("/users/:id/posts/:post").unwrap();
+
+        let params = pattern.matches("/users/42/posts/7").unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.get("post"), Some("7"));
+
+        assert_eq!(pattern.matches("/users/42/posts"), None);
+        assert_eq!(pattern.matches("/users/42/posts/7/edit"), None);
+    }
+
+    #[test]
+    fn wildcards_capture_the_rest() {
+        let pattern = RoutePattern::parse("/files/*path").unwrap();
+
+        let params = pattern.matches("/files/css/vendor/app.css").unwrap();
+        assert_eq!(params.get("path"), Some("css/vendor/app.css"));
+
+        assert_eq!(pattern.matches("/other/app.css"), None);
+    }
+
+    #[test]
+    fn more_specific_patterns_rank_higher() {
+        let param = RoutePattern::parse("/users/:id").unwrap();
+        let wildcard = RoutePattern::parse("/users/*rest").unwrap();
+        let literal = RoutePattern::parse("/users/admin/*rest").unwrap();
+
+        assert!(param.specificity() > wildcard.specificity());
+        assert!(literal.specificity() > param.specificity());
+    }
+}
@@ -0,0 +1,228 @@
+use http::{Method, Request, Response};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use super::{Handler, Redirect, RoutePattern, Router, Routes};
+
+/// The routes served for one host.
+///
+/// Cloning a site gives another handle to the same routes, so routes added
+/// through either are served.
+#[derive(Clone)]
+pub struct Site {
+    pub(super) routes: Arc<RwLock<Router>>,
+}
+
+impl Site {
+    pub(super) fn new(routes: Routes) -> Site {
+        Site {
+            routes: Arc::new(RwLock::new(Router::new(routes))),
+        }
+    }
+
+    /// Maps a path onto a file, replacing any existing route for it.
+    ///
+    /// This can be done while the server is running.
+    pub fn add_route(&self, path: &str, file: &str) {
+        self.routes
+            .write()
+            .unwrap()
+            .files
+            .insert(String::from(path), String::from(file));
+    }
+
+    /// Stops serving a file at a path.
+    ///
+    /// This can be done while the server is running.
+    pub fn remove_route(&self, path: &str) {
+        self.routes.write().unwrap().files.remove(path);
+    }
+
+    /// Serves the files under `fs_root` from URLs beginning with `url_prefix`.
+    ///
+    /// With `serve_dir("/static/", "public")`, a request for
+    /// `/static/css/app.css` is served from `public/css/app.css`. Requests
+    /// that try to climb out of `fs_root` are refused with a 403.
+    pub fn serve_dir(&self, url_prefix: &str, fs_root: &str) {
+        let prefix = String::from(url_prefix.trim_end_matches('/'));
+
+        let mut routes = self.routes.write().unwrap();
+        routes.dirs.retain(|(existing, _)| *existing != prefix);
+        routes.dirs.push((prefix, PathBuf::from(fs_root)));
+    }
+
+    /// Answers requests whose path matches `pattern` by calling `handler`.
+    ///
+    /// Patterns match anywhere in the path unless anchored with `^` and `$`.
+    /// Named capture groups are put in the request's extensions as
+    /// `PathParams`. Regex routes are only tried once no other route matches.
+    pub fn route_regex<F>(&self, pattern: regex::Regex, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        let handler: Handler = Box::new(handler);
+
+        self.routes
+            .write()
+            .unwrap()
+            .regexes
+            .push((pattern, Arc::new(handler)));
+    }
+
+    /// Sends clients asking for `from` on to `to`.
+    ///
+    /// Permanent redirects answer with a 301, temporary ones with a 302.
+    /// Redirects take priority over files routed to the same path.
+    pub fn redirect(&self, from: &str, to: &str, permanent: bool) {
+        let redirect = Redirect {
+            to: String::from(to),
+            permanent,
+        };
+
+        self.routes
+            .write()
+            .unwrap()
+            .redirects
+            .insert(String::from(from), redirect);
+    }
+
+    /// Answers requests to a path by calling `handler`.
+    ///
+    /// Handlers take priority over files routed to the same path. Segments of
+    /// the path written as `:name` match any one segment, and a final `*name`
+    /// matches the rest of the path, as in `/users/:id` or `/files/*path`.
+    /// What they matched is put in the request's extensions as `PathParams`.
+    /// When several routes match, the most specific one is used.
+    pub fn route<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        let handler: Handler = Box::new(handler);
+        let handler = Arc::new(handler);
+
+        let mut routes = self.routes.write().unwrap();
+
+        match RoutePattern::parse(path) {
+            Some(pattern) => {
+                routes.patterns.retain(|(existing, _)| *existing != pattern);
+                routes.patterns.push((pattern, handler));
+            }
+            None => {
+                routes.handlers.insert(String::from(path), handler);
+            }
+        }
+    }
+
+    /// Answers requests using one method to a path by calling `handler`.
+    ///
+    /// A path can have a different handler for each method, and takes
+    /// priority over every other kind of route. Requests using a method the
+    /// path has no handler for are refused with a 405 listing the ones it
+    /// does. HEAD requests are answered by the GET handler unless the path
+    /// has one of its own.
+    pub fn route_method<F>(&self, method: Method, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        let handler: Handler = Box::new(handler);
+
+        self.routes
+            .write()
+            .unwrap()
+            .method_handlers
+            .entry(String::from(path))
+            .or_default()
+            .insert(method, Arc::new(handler));
+    }
+
+    /// Answers GET requests to a path by calling `handler`.
+    ///
+    /// See `route_method`.
+    pub fn get<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.route_method(Method::GET, path, handler);
+    }
+
+    /// Answers POST requests to a path by calling `handler`.
+    ///
+    /// See `route_method`.
+    pub fn post<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&Request<Vec<u8>>) -> Response<String> + Send + Sync + 'static,
+    {
+        self.route_method(Method::POST, path, handler);
+    }
+}
+
+/// The routes for every host a server answers to.
+pub(super) struct Sites {
+    pub(super) default: Site,
+    /// Keyed by lowercased hostname.
+    pub(super) hosts: RwLock<HashMap<String, Site>>,
+}
+
+impl Sites {
+    pub(super) fn new(routes: Routes) -> Sites {
+        Sites {
+            default: Site::new(routes),
+            hosts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Finds the site serving a request.
+    ///
+    /// Unknown hosts get the default site, or nothing when `strict`.
+    pub(super) fn find<B>(&self, request: &Request<B>, strict: bool) -> Option<Site> {
+        let site = host(request).and_then(|host| self.hosts.read().unwrap().get(&host).cloned());
+
+        match site {
+            Some(site) => Some(site),
+            None if strict => None,
+            None => Some(self.default.clone()),
+        }
+    }
+}
+
+/// Gets the lowercased hostname a request is for, without any port.
+fn host<B>(request: &Request<B>) -> Option<String> {
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => request.headers().get(http::header::HOST)?.to_str().ok()?,
+    };
+
+    // Bracketed IPv6 addresses have colons of their own
+    let host = match host.rfind(']') {
+        Some(end) => &host[..=end],
+        None => host.split(':').next().unwrap_or(host),
+    };
+
+    Some(host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str) -> Request<()> {
+        Request::builder()
+            .uri("/")
+            .header(http::header::HOST, host)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn hosts_ignore_case_and_port() {
+        assert_eq!(
+            host(&request("Example.COM:8080")),
+            Some(String::from("example.com"))
+        );
+        assert_eq!(host(&request("[::1]:8080")), Some(String::from("[::1]")));
+        assert_eq!(host(&Request::new(())), None);
+    }
+}